@@ -78,6 +78,9 @@ declare global {
       zeroCopy?: ArrayBufferView | null
     ): Uint8Array | null;
     setAsyncHandler(opId: number, cb: (msg: Uint8Array) => void): void;
+    setAbortHandler(opId: number, cb: (error: Error) => void): void;
+    fastChannel(): Uint8Array | null;
+    dispatchFast(opId: number, controlLength: number): Uint8Array | null;
     sharedQueue: {
       head(): number;
       numRecords(): number;
@@ -106,6 +109,11 @@ declare global {
       scriptName?: string
     ): [unknown, EvalErrorInfo | null];
 
+    evalToJson(code: string): [string | null, EvalErrorInfo | null];
+
+    /** Only available if the isolate was built with `expose_gc`. */
+    gc?(): void;
+
     formatError: (e: Error) => string;
 
     /**
@@ -122,6 +130,15 @@ declare global {
 
     decode(bytes: Uint8Array): string;
     encode(text: string): Uint8Array;
+
+    jsonParse(json: string): unknown;
+    jsonStringify(value: unknown): string | undefined;
+
+    inspect(value: unknown, options?: { depth?: number }): string;
+
+    now(): number;
+
+    dispatchConsole(method: string, args: unknown[]): void;
   }
 
   // Only `var` variables show up in the `globalThis` type when doing a global
@@ -211,6 +211,9 @@ declare function removeEventListener(
 declare interface ImportMeta {
   url: string;
   main: boolean;
+  /** Resolves a module specifier relative to the current module, returning
+   * the resolved URL. */
+  resolve(specifier: string): string;
 }
 
 interface DomIterable<K, V> {
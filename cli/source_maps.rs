@@ -90,6 +90,9 @@ pub fn apply_source_map<G: SourceMapGetter>(
 
   deno_core::JSError {
     message: js_error.message.clone(),
+    name: js_error.name.clone(),
+    exception_message: js_error.exception_message.clone(),
+    stack: js_error.stack.clone(),
     source_line,
     script_resource_name,
     line_number,
@@ -97,6 +100,9 @@ pub fn apply_source_map<G: SourceMapGetter>(
     end_column,
     frames: js_error.frames.clone(),
     formatted_frames: js_error.formatted_frames.clone(),
+    is_terminated: js_error.is_terminated,
+    cause: js_error.cause.clone(),
+    errors: js_error.errors.clone(),
   }
 }
 
@@ -217,6 +223,9 @@ mod tests {
   fn apply_source_map_line() {
     let e = deno_core::JSError {
       message: "TypeError: baz".to_string(),
+      name: None,
+      exception_message: None,
+      stack: None,
       source_line: Some("foo".to_string()),
       script_resource_name: Some("foo_bar.ts".to_string()),
       line_number: Some(4),
@@ -224,6 +233,9 @@ mod tests {
       end_column: None,
       frames: vec![],
       formatted_frames: vec![],
+      is_terminated: false,
+      cause: None,
+      errors: vec![],
     };
     let getter = MockSourceMapGetter {};
     let actual = apply_source_map(&e, &getter);
@@ -108,6 +108,15 @@ impl State {
           );
           Op::Sync(buf)
         }
+        Op::SyncI32(value) => {
+          let mut state_ = state.borrow_mut();
+          state_.metrics.op_sync(
+            bytes_sent_control,
+            bytes_sent_zero_copy,
+            std::mem::size_of::<i32>() as u64,
+          );
+          Op::SyncI32(value)
+        }
         Op::Async(fut) => {
           let mut state_ = state.borrow_mut();
           state_
@@ -224,6 +233,8 @@ impl ModuleLoader for State {
         code: compiled_module.code,
         module_url_specified,
         module_url_found: compiled_module.name,
+        line_offset: 0,
+        column_offset: 0,
       })
     };
 
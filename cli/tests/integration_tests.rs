@@ -2107,6 +2107,24 @@ fn extract_ws_url_from_stderr(
   url::Url::parse(ws_url).unwrap()
 }
 
+#[test]
+fn print_flushes_without_newline() {
+  use std::io::Read;
+  let mut child = util::deno_cmd()
+    .arg("eval")
+    .arg("Deno.core.print('ready'); setTimeout(() => {}, 60000);")
+    .stdout(std::process::Stdio::piped())
+    .spawn()
+    .unwrap();
+  // The process is still running, so the bytes can only be read if print
+  // flushed them.
+  let mut buf = [0; 5];
+  child.stdout.as_mut().unwrap().read_exact(&mut buf).unwrap();
+  assert_eq!(&buf, b"ready");
+  child.kill().unwrap();
+  child.wait().unwrap();
+}
+
 #[tokio::test]
 async fn inspector_connect() {
   let script = deno::test_util::root_path()
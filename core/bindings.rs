@@ -2,105 +2,254 @@
 
 use crate::es_isolate::EsIsolate;
 use crate::isolate::Isolate;
+use crate::isolate::PendingTimer;
+use crate::isolate::RealmId;
+use crate::isolate::SyncResponse;
 use crate::isolate::ZeroCopyBuf;
+use crate::isolate::MAIN_REALM;
 use crate::js_errors::JSError;
+use crate::ops::OpId;
 
 use rusty_v8 as v8;
 use v8::MapFnTo;
 
 use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::io::Write;
 use std::option::Option;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
 use url::Url;
 
 lazy_static! {
   pub static ref EXTERNAL_REFERENCES: v8::ExternalReferences =
-    v8::ExternalReferences::new(&[
-      v8::ExternalReference {
-        function: print.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: recv.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: send.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: set_macrotask_callback.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: eval_context.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: format_error.map_fn_to()
-      },
-      v8::ExternalReference {
-        getter: shared_getter.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: queue_microtask.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: encode.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: decode.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: get_promise_details.map_fn_to(),
-      }
-    ]);
+    external_references(&[]);
+}
+
+fn builtin_external_references() -> Vec<v8::ExternalReference<'static>> {
+  vec![
+    v8::ExternalReference {
+      function: print.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: recv.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: send.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_macrotask_callback.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: eval_context.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: format_error.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: eval_to_json.map_fn_to(),
+    },
+    v8::ExternalReference {
+      getter: shared_getter.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: queue_microtask.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: encode.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: decode.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: inspect.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: now.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: get_promise_details.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: import_meta_resolve.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: gc.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_timeout.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: clear_timeout.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: dispatch_console.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: get_fast_channel.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: send_fast.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: json_parse.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: json_stringify.map_fn_to(),
+    },
+  ]
+}
+
+/// Returns a table of external references to create an isolate with: the
+/// built-in bindings followed by `extra`.
+pub fn external_references(
+  extra: &[v8::ExternalReference],
+) -> v8::ExternalReferences {
+  let mut references = builtin_external_references();
+  references.extend_from_slice(extra);
+  v8::ExternalReferences::new(&references)
+}
+
+/// Returns a script id that is unique for the lifetime of the process, to be
+/// used in a `v8::ScriptOrigin`.
+pub fn next_script_id() -> i32 {
+  static NEXT_SCRIPT_ID: AtomicI32 = AtomicI32::new(1);
+  NEXT_SCRIPT_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Returns the URL given by a trailing `//# sourceMappingURL=` comment in
+/// `source`, if any.
+pub fn source_map_url_from_source(source: &str) -> Option<&str> {
+  const PREFIX: &str = "//# sourceMappingURL=";
+  let line = source.trim_end().lines().last()?.trim_start();
+  if line.starts_with(PREFIX) {
+    let url = line[PREFIX.len()..].trim();
+    if !url.is_empty() {
+      return Some(url);
+    }
+  }
+  None
 }
 
 pub fn script_origin<'a>(
   s: &mut impl v8::ToLocal<'a>,
   resource_name: v8::Local<'a, v8::String>,
+  source_map_url: Option<&str>,
+  script_id: i32,
 ) -> v8::ScriptOrigin<'a> {
-  let resource_line_offset = v8::Integer::new(s, 0);
-  let resource_column_offset = v8::Integer::new(s, 0);
-  let resource_is_shared_cross_origin = v8::Boolean::new(s, false);
-  let script_id = v8::Integer::new(s, 123);
-  let source_map_url = v8::String::new(s, "source_map_url").unwrap();
-  let resource_is_opaque = v8::Boolean::new(s, true);
-  let is_wasm = v8::Boolean::new(s, false);
-  let is_module = v8::Boolean::new(s, false);
-  v8::ScriptOrigin::new(
-    resource_name.into(),
-    resource_line_offset,
-    resource_column_offset,
-    resource_is_shared_cross_origin,
+  origin(s, resource_name, source_map_url, script_id, false, 0, 0)
+}
+
+/// Same as `script_origin()`, for source that starts at the given zero-based
+/// line and column of `resource_name`.
+pub fn script_origin_with_offset<'a>(
+  s: &mut impl v8::ToLocal<'a>,
+  resource_name: v8::Local<'a, v8::String>,
+  source_map_url: Option<&str>,
+  script_id: i32,
+  line_offset: i32,
+  column_offset: i32,
+) -> v8::ScriptOrigin<'a> {
+  origin(
+    s,
+    resource_name,
+    source_map_url,
     script_id,
-    source_map_url.into(),
-    resource_is_opaque,
-    is_wasm,
-    is_module,
+    false,
+    line_offset,
+    column_offset,
   )
 }
 
 pub fn module_origin<'a>(
   s: &mut impl v8::ToLocal<'a>,
   resource_name: v8::Local<'a, v8::String>,
+  source_map_url: Option<&str>,
+  script_id: i32,
+) -> v8::ScriptOrigin<'a> {
+  origin(s, resource_name, source_map_url, script_id, true, 0, 0)
+}
+
+/// Same as `module_origin()`, for source that starts at the given zero-based
+/// line and column of `resource_name`.
+pub fn module_origin_with_offset<'a>(
+  s: &mut impl v8::ToLocal<'a>,
+  resource_name: v8::Local<'a, v8::String>,
+  source_map_url: Option<&str>,
+  script_id: i32,
+  line_offset: i32,
+  column_offset: i32,
+) -> v8::ScriptOrigin<'a> {
+  origin(
+    s,
+    resource_name,
+    source_map_url,
+    script_id,
+    true,
+    line_offset,
+    column_offset,
+  )
+}
+
+fn origin<'a>(
+  s: &mut impl v8::ToLocal<'a>,
+  resource_name: v8::Local<'a, v8::String>,
+  source_map_url: Option<&str>,
+  script_id: i32,
+  is_module: bool,
+  line_offset: i32,
+  column_offset: i32,
 ) -> v8::ScriptOrigin<'a> {
-  let resource_line_offset = v8::Integer::new(s, 0);
-  let resource_column_offset = v8::Integer::new(s, 0);
+  let resource_line_offset = v8::Integer::new(s, line_offset);
+  let resource_column_offset = v8::Integer::new(s, column_offset);
   let resource_is_shared_cross_origin = v8::Boolean::new(s, false);
-  let script_id = v8::Integer::new(s, 123);
-  let source_map_url = v8::String::new(s, "source_map_url").unwrap();
+  let script_id = v8::Integer::new(s, script_id);
+  let source_map_url: v8::Local<v8::Value> = match source_map_url {
+    Some(url) => v8::String::new(s, url).unwrap().into(),
+    None => v8::undefined(s).into(),
+  };
   let resource_is_opaque = v8::Boolean::new(s, true);
   let is_wasm = v8::Boolean::new(s, false);
-  let is_module = v8::Boolean::new(s, true);
+  let is_module = v8::Boolean::new(s, is_module);
   v8::ScriptOrigin::new(
     resource_name.into(),
     resource_line_offset,
     resource_column_offset,
     resource_is_shared_cross_origin,
     script_id,
-    source_map_url.into(),
+    source_map_url,
     resource_is_opaque,
     is_wasm,
     is_module,
   )
 }
 
+/// Returns the V8 string for a frequently used property key. The string is
+/// created once per isolate and cached, which saves allocating it again on
+/// hot paths like error serialization.
+pub fn v8_key<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  key: &'static str,
+) -> v8::Local<'s, v8::String> {
+  let data = scope.isolate().get_data(0);
+  if data.is_null() {
+    // Not a deno_core isolate; there's nowhere to cache the key.
+    return v8::String::new(scope, key).unwrap();
+  }
+  let deno_isolate: &mut Isolate = unsafe { &mut *(data as *mut Isolate) };
+  let global = deno_isolate
+    .string_keys
+    .entry(key)
+    .or_insert_with(v8::Global::<v8::String>::new);
+  if global.is_empty() {
+    let key_str = v8::String::new(scope, key).unwrap();
+    global.set(scope, key_str);
+  }
+  global.get(scope).unwrap()
+}
+
 pub fn initialize_context<'s>(
   scope: &mut impl v8::ToLocal<'s>,
 ) -> v8::Local<'s, v8::Context> {
@@ -173,6 +322,15 @@ pub fn initialize_context<'s>(
     eval_context_val.into(),
   );
 
+  let mut eval_to_json_tmpl = v8::FunctionTemplate::new(scope, eval_to_json);
+  let eval_to_json_val =
+    eval_to_json_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "evalToJson").unwrap().into(),
+    eval_to_json_val.into(),
+  );
+
   let mut format_error_tmpl = v8::FunctionTemplate::new(scope, format_error);
   let format_error_val =
     format_error_tmpl.get_function(scope, context).unwrap();
@@ -198,6 +356,68 @@ pub fn initialize_context<'s>(
     decode_val.into(),
   );
 
+  let mut json_parse_tmpl = v8::FunctionTemplate::new(scope, json_parse);
+  let json_parse_val = json_parse_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "jsonParse").unwrap().into(),
+    json_parse_val.into(),
+  );
+
+  let mut json_stringify_tmpl =
+    v8::FunctionTemplate::new(scope, json_stringify);
+  let json_stringify_val =
+    json_stringify_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "jsonStringify").unwrap().into(),
+    json_stringify_val.into(),
+  );
+
+  let mut inspect_tmpl = v8::FunctionTemplate::new(scope, inspect);
+  let inspect_val = inspect_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "inspect").unwrap().into(),
+    inspect_val.into(),
+  );
+
+  let mut now_tmpl = v8::FunctionTemplate::new(scope, now);
+  let now_val = now_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "now").unwrap().into(),
+    now_val.into(),
+  );
+
+  let mut get_fast_channel_tmpl =
+    v8::FunctionTemplate::new(scope, get_fast_channel);
+  let get_fast_channel_val =
+    get_fast_channel_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "getFastChannel").unwrap().into(),
+    get_fast_channel_val.into(),
+  );
+
+  let mut send_fast_tmpl = v8::FunctionTemplate::new(scope, send_fast);
+  let send_fast_val = send_fast_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "sendFast").unwrap().into(),
+    send_fast_val.into(),
+  );
+
+  let mut dispatch_console_tmpl =
+    v8::FunctionTemplate::new(scope, dispatch_console);
+  let dispatch_console_val =
+    dispatch_console_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "dispatchConsole").unwrap().into(),
+    dispatch_console_val.into(),
+  );
+
   let mut get_promise_details_tmpl =
     v8::FunctionTemplate::new(scope, get_promise_details);
   let get_promise_details_val = get_promise_details_tmpl
@@ -234,12 +454,19 @@ pub fn boxed_slice_to_uint8array<'sc>(
   buf: Box<[u8]>,
 ) -> v8::Local<'sc, v8::Uint8Array> {
   assert!(!buf.is_empty());
-  let buf_len = buf.len();
   let backing_store = v8::ArrayBuffer::new_backing_store_from_boxed_slice(buf);
-  let mut backing_store_shared = backing_store.make_shared();
-  let ab =
-    v8::ArrayBuffer::with_backing_store(scope, &mut backing_store_shared);
-  v8::Uint8Array::new(ab, 0, buf_len).expect("Failed to create UintArray8")
+  backing_store_to_uint8array(scope, backing_store.make_shared())
+}
+
+/// Wraps `backing_store` in an ArrayBuffer without copying it. The
+/// ArrayBuffer holds a reference to the backing store.
+pub fn backing_store_to_uint8array<'sc>(
+  scope: &mut impl v8::ToLocal<'sc>,
+  mut backing_store: v8::SharedRef<v8::BackingStore>,
+) -> v8::Local<'sc, v8::Uint8Array> {
+  let byte_length = backing_store.byte_length();
+  let ab = v8::ArrayBuffer::with_backing_store(scope, &mut backing_store);
+  v8::Uint8Array::new(ab, 0, byte_length).expect("Failed to create UintArray8")
 }
 
 pub extern "C" fn host_import_module_dynamically_callback(
@@ -265,15 +492,58 @@ pub extern "C" fn host_import_module_dynamically_callback(
     .unwrap()
     .to_rust_string_lossy(scope);
 
-  // TODO(ry) I'm not sure what HostDefinedOptions is for or if we're ever going
-  // to use it. For now we check that it is not used. This check may need to be
-  // changed in the future.
-  let host_defined_options = referrer.get_host_defined_options();
-  assert_eq!(host_defined_options.length(), 0);
+  // Options V8 recorded in the referrer's ScriptOrigin come first, followed by
+  // those set with `EsIsolate::set_host_defined_options()`.
+  let v8_options = referrer.get_host_defined_options();
+  let mut host_defined_options: Vec<String> = vec![];
+  for i in 0..v8_options.length() {
+    let option: v8::Local<v8::Value> = v8_options.get(scope, i).into();
+    if let Ok(option) = v8::Local::<v8::String>::try_from(option) {
+      host_defined_options.push(option.to_rust_string_lossy(scope));
+    }
+  }
+  if let Some(options) =
+    deno_isolate.host_defined_options.get(&referrer_name_str)
+  {
+    host_defined_options.extend(options.iter().cloned());
+  }
 
   let mut resolver = v8::PromiseResolver::new(scope, context).unwrap();
   let promise = resolver.get_promise(scope);
 
+  if let Err(err) = deno_isolate.loader.check_dynamic_import(
+    &specifier_str,
+    &referrer_name_str,
+    &host_defined_options,
+  ) {
+    let message = v8::String::new(scope, &err.to_string()).unwrap();
+    let exception = v8::Exception::type_error(scope, message);
+    resolver.reject(context, exception).unwrap();
+    return &mut *scope.escape(promise);
+  }
+
+  // The specifier is resolved only once; the load below reuses the result.
+  let module_specifier = deno_isolate
+    .loader
+    .resolve(&specifier_str, &referrer_name_str, false)
+    .ok();
+
+  // Modules that are already registered and evaluated, e.g. because a
+  // bundled app registered all of its modules up front, are imported right
+  // away instead of going through the loader.
+  if let Some(id) = module_specifier
+    .as_ref()
+    .and_then(|s| deno_isolate.modules.get_id(s.as_str()))
+  {
+    let info = deno_isolate.modules.get_info(id).unwrap();
+    let module = info.handle.get(scope).unwrap();
+    if module.get_status() == v8::ModuleStatus::Evaluated {
+      let namespace = module.get_module_namespace();
+      resolver.resolve(context, namespace).unwrap();
+      return &mut *scope.escape(promise);
+    }
+  }
+
   let mut resolver_handle = v8::Global::new();
   resolver_handle.set(scope, resolver);
 
@@ -283,7 +553,15 @@ pub extern "C" fn host_import_module_dynamically_callback(
     .dyn_import_map
     .insert(import_id, resolver_handle);
 
-  deno_isolate.dyn_import_cb(&specifier_str, &referrer_name_str, import_id);
+  match module_specifier {
+    Some(module_specifier) => {
+      deno_isolate.dyn_import_resolved_cb(module_specifier, import_id)
+    }
+    // The load reports the resolution error.
+    None => {
+      deno_isolate.dyn_import_cb(&specifier_str, &referrer_name_str, import_id)
+    }
+  }
 
   &mut *scope.escape(promise)
 }
@@ -300,21 +578,151 @@ pub extern "C" fn host_initialize_import_meta_object_callback(
   let deno_isolate: &mut EsIsolate =
     unsafe { &mut *(isolate.get_data(1) as *mut EsIsolate) };
 
-  let id = module.get_identity_hash();
-  assert_ne!(id, 0);
-
-  let info = deno_isolate.modules.get_info(id).expect("Module not found");
+  // Modules run by `Isolate::execute_module()` aren't registered.
+  let id = match deno_isolate
+    .modules
+    .get_id_by_identity_hash(module.get_identity_hash())
+  {
+    Some(id) => id,
+    None => return,
+  };
+  let info = deno_isolate.modules.get_info(id).unwrap();
 
   meta.create_data_property(
     context,
-    v8::String::new(scope, "url").unwrap().into(),
+    v8_key(scope, "url").into(),
     v8::String::new(scope, &info.name).unwrap().into(),
   );
   meta.create_data_property(
     context,
-    v8::String::new(scope, "main").unwrap().into(),
+    v8_key(scope, "main").into(),
     v8::Boolean::new(scope, info.main).into(),
   );
+
+  // `import.meta.resolve()` resolves relative to this module, whose id is
+  // passed as the function's data.
+  let referrer_id = v8::Integer::new(scope, id);
+  let mut resolve_tmpl = v8::FunctionTemplate::new_with_data(
+    scope,
+    import_meta_resolve,
+    referrer_id.into(),
+  );
+  let resolve_val = resolve_tmpl.get_function(scope, context).unwrap();
+  meta.create_data_property(
+    context,
+    v8_key(scope, "resolve").into(),
+    resolve_val.into(),
+  );
+}
+
+/// Installs the global `setTimeout()` and `clearTimeout()`, see
+/// `Isolate::register_timer_op()`.
+pub fn expose_timers<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  context: v8::Local<v8::Context>,
+) {
+  let global = context.global(scope);
+
+  let mut set_timeout_tmpl = v8::FunctionTemplate::new(scope, set_timeout);
+  let set_timeout_val = set_timeout_tmpl.get_function(scope, context).unwrap();
+  global.set(
+    context,
+    v8::String::new(scope, "setTimeout").unwrap().into(),
+    set_timeout_val.into(),
+  );
+
+  let mut clear_timeout_tmpl = v8::FunctionTemplate::new(scope, clear_timeout);
+  let clear_timeout_val =
+    clear_timeout_tmpl.get_function(scope, context).unwrap();
+  global.set(
+    context,
+    v8::String::new(scope, "clearTimeout").unwrap().into(),
+    clear_timeout_val.into(),
+  );
+}
+
+/// Adds `Deno.core.gc()`, see `IsolateBuilder::expose_gc()`.
+pub fn expose_gc<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  context: v8::Local<v8::Context>,
+) {
+  let global = context.global(scope);
+  let deno_val: v8::Local<v8::Object> = global
+    .get(
+      scope,
+      context,
+      v8::String::new(scope, "Deno").unwrap().into(),
+    )
+    .unwrap()
+    .try_into()
+    .unwrap();
+  let mut core_val: v8::Local<v8::Object> = deno_val
+    .get(
+      scope,
+      context,
+      v8::String::new(scope, "core").unwrap().into(),
+    )
+    .unwrap()
+    .try_into()
+    .unwrap();
+
+  let mut gc_tmpl = v8::FunctionTemplate::new(scope, gc);
+  let gc_val = gc_tmpl.get_function(scope, context).unwrap();
+  core_val.set(
+    context,
+    v8::String::new(scope, "gc").unwrap().into(),
+    gc_val.into(),
+  );
+}
+
+fn gc(
+  scope: v8::FunctionCallbackScope,
+  _args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  scope.isolate().low_memory_notification();
+}
+
+fn import_meta_resolve(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut EsIsolate =
+    unsafe { &mut *(scope.isolate().get_data(1) as *mut EsIsolate) };
+
+  if args.length() < 1 {
+    let msg = v8::String::new(scope, "Missing specifier").unwrap();
+    let exception = v8::Exception::type_error(scope, msg);
+    scope.isolate().throw_exception(exception);
+    return;
+  }
+
+  let context = scope.get_current_context().unwrap();
+  let referrer_id = args.data().unwrap().int32_value(context).unwrap();
+  let specifier = match args.get(0).to_string(scope) {
+    Some(specifier) => specifier.to_rust_string_lossy(scope),
+    // An exception was thrown while converting the specifier.
+    None => return,
+  };
+
+  let msg = match deno_isolate.resolve_from_module(&specifier, referrer_id) {
+    Some(Ok(resolved)) => {
+      let resolved = v8::String::new(scope, resolved.as_str()).unwrap();
+      rv.set(resolved.into());
+      return;
+    }
+    Some(Err(err)) => {
+      format!("Cannot resolve module \"{}\": {}", specifier, err)
+    }
+    None => format!(
+      "Cannot resolve module \"{}\": the importing module has been removed",
+      specifier
+    ),
+  };
+  let msg = v8::String::new(scope, &msg).unwrap();
+  let exception = v8::Exception::type_error(scope, msg);
+  scope.isolate().throw_exception(exception);
 }
 
 pub extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
@@ -382,10 +790,107 @@ fn print(
     Some(s) => s,
     None => v8::String::new(scope, "").unwrap(),
   };
+  let text = str_.to_rust_string_lossy(scope);
+  #[cfg(windows)]
+  {
+    if win_console::write(&text, is_err) {
+      return;
+    }
+  }
+  // Flush explicitly, so output without a trailing newline isn't held back
+  // when stdout is a pipe.
   if is_err {
-    eprint!("{}", str_.to_rust_string_lossy(scope));
+    eprint!("{}", text);
+    let _ = std::io::stderr().flush();
   } else {
-    print!("{}", str_.to_rust_string_lossy(scope));
+    print!("{}", text);
+    let _ = std::io::stdout().flush();
+  }
+}
+
+#[cfg(windows)]
+mod win_console {
+  use std::io::Write;
+  use std::ptr::null_mut;
+  use winapi::shared::minwindef::DWORD;
+  use winapi::um::consoleapi::GetConsoleMode;
+  use winapi::um::consoleapi::WriteConsoleW;
+  use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+  use winapi::um::processenv::GetStdHandle;
+  use winapi::um::winbase::STD_ERROR_HANDLE;
+  use winapi::um::winbase::STD_OUTPUT_HANDLE;
+
+  /// Maximum number of UTF-16 code units passed to a single WriteConsoleW
+  /// call. Older versions of Windows fail on larger writes.
+  const CHUNK_SIZE: usize = 8192;
+
+  /// Writes `text` as UTF-16 with WriteConsoleW, so non-ASCII characters are
+  /// displayed correctly regardless of the console code page. Returns false,
+  /// without writing anything, if the stream is not attached to a console,
+  /// e.g. because it has been redirected to a file or a pipe, or if the first
+  /// write fails. If a later write fails, the rest of `text` is written to
+  /// the Rust stream instead.
+  pub fn write(text: &str, is_err: bool) -> bool {
+    let std_handle = if is_err {
+      STD_ERROR_HANDLE
+    } else {
+      STD_OUTPUT_HANDLE
+    };
+    let handle = unsafe { GetStdHandle(std_handle) };
+    if handle.is_null() || handle == INVALID_HANDLE_VALUE {
+      return false;
+    }
+    let mut mode: DWORD = 0;
+    if unsafe { GetConsoleMode(handle, &mut mode) } == 0 {
+      return false;
+    }
+
+    // Anything still buffered by the Rust stream must come out first.
+    if is_err {
+      let _ = std::io::stderr().flush();
+    } else {
+      let _ = std::io::stdout().flush();
+    }
+
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+    let mut rest = &utf16[..];
+    while !rest.is_empty() {
+      let mut len = rest.len().min(CHUNK_SIZE);
+      // Don't split a surrogate pair between two writes.
+      if len < rest.len() && is_high_surrogate(rest[len - 1]) {
+        len -= 1;
+      }
+      let mut written: DWORD = 0;
+      let ok = unsafe {
+        WriteConsoleW(
+          handle,
+          rest.as_ptr() as *const _,
+          len as DWORD,
+          &mut written,
+          null_mut(),
+        )
+      };
+      if ok == 0 || written == 0 {
+        if rest.len() == utf16.len() {
+          return false;
+        }
+        let rest = String::from_utf16_lossy(rest);
+        if is_err {
+          eprint!("{}", rest);
+          let _ = std::io::stderr().flush();
+        } else {
+          print!("{}", rest);
+          let _ = std::io::stdout().flush();
+        }
+        break;
+      }
+      rest = &rest[written as usize..];
+    }
+    true
+  }
+
+  fn is_high_surrogate(c: u16) -> bool {
+    (0xD800..=0xDBFF).contains(&c)
   }
 }
 
@@ -397,14 +902,42 @@ fn recv(
   let deno_isolate: &mut Isolate =
     unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
 
-  if !deno_isolate.js_recv_cb.is_empty() {
+  let recv_fn = match v8::Local::<v8::Function>::try_from(args.get(0)) {
+    Ok(recv_fn) => recv_fn,
+    Err(_) => {
+      let msg =
+        v8::String::new(scope, "Deno.core.recv expects a function").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+
+  // The callback belongs to the context it is registered from: the main
+  // context or a realm. Any other context, e.g. one whose handle has been
+  // released for a snapshot, can't receive responses.
+  let js_recv_cb = match deno_isolate.current_realm(scope) {
+    Some(MAIN_REALM) => &mut deno_isolate.js_recv_cb,
+    Some(realm_id) => &mut deno_isolate.realms[realm_id - 1].js_recv_cb,
+    None => {
+      let msg = v8::String::new(
+        scope,
+        "Deno.core.recv called from a context that can't receive responses",
+      )
+      .unwrap();
+      let exception = v8::Exception::error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+
+  if !js_recv_cb.is_empty() {
     let msg = v8::String::new(scope, "Deno.core.recv already called.").unwrap();
     scope.isolate().throw_exception(msg.into());
     return;
   }
 
-  let recv_fn = v8::Local::<v8::Function>::try_from(args.get(0)).unwrap();
-  deno_isolate.js_recv_cb.set(scope, recv_fn);
+  js_recv_cb.set(scope, recv_fn);
 }
 
 fn send(
@@ -429,6 +962,9 @@ fn send(
 
   let control = match v8::Local::<v8::ArrayBufferView>::try_from(args.get(1)) {
     Ok(view) => {
+      if !check_not_detached(scope, view) {
+        return;
+      }
       let byte_offset = view.byte_offset();
       let byte_length = view.byte_length();
       let backing_store = view.buffer().unwrap().get_backing_store();
@@ -439,9 +975,15 @@ fn send(
   };
 
   let zero_copy: Option<ZeroCopyBuf> =
-    v8::Local::<v8::ArrayBufferView>::try_from(args.get(2))
-      .map(ZeroCopyBuf::new)
-      .ok();
+    match v8::Local::<v8::ArrayBufferView>::try_from(args.get(2)) {
+      Ok(view) => {
+        if !check_not_detached(scope, view) {
+          return;
+        }
+        Some(ZeroCopyBuf::new(view))
+      }
+      Err(..) => None,
+    };
 
   // If response is empty then it's either async op or exception was thrown
   let maybe_response =
@@ -450,11 +992,295 @@ fn send(
   if let Some(response) = maybe_response {
     // Synchronous response.
     // Note op_id is not passed back in the case of synchronous response.
-    let (_op_id, buf) = response;
+    match response {
+      (_op_id, SyncResponse::Buf(buf)) => {
+        if !buf.is_empty() {
+          let ui8 = boxed_slice_to_uint8array(scope, buf);
+          rv.set(ui8.into())
+        }
+      }
+      (_op_id, SyncResponse::I32(value)) => {
+        rv.set(v8::Integer::new(scope, value).into())
+      }
+    }
+  }
+}
 
-    if !buf.is_empty() {
-      let ui8 = boxed_slice_to_uint8array(scope, buf);
-      rv.set(ui8.into())
+// Schedules `fn` to be called after `ms` milliseconds by sending the timer
+// op registered with `Isolate::register_timer_op()`. Returns the timer id.
+fn set_timeout(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+
+  let callback = match v8::Local::<v8::Function>::try_from(args.get(0)) {
+    Ok(callback) => callback,
+    Err(_) => {
+      let msg = v8::String::new(scope, "Callback must be a function").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  let op_id = match deno_isolate.timer_op_id() {
+    Some(op_id) => op_id,
+    None => {
+      let msg =
+        v8::String::new(scope, "No timer op has been registered").unwrap();
+      let exception = v8::Exception::error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  let delay = args
+    .get(1)
+    .number_value(scope)
+    .filter(|ms| ms.is_finite() && *ms > 0.0)
+    .map(|ms| ms.min(f64::from(u32::MAX)) as u32)
+    .unwrap_or(0);
+
+  // Ids wrap around, skipping 0 and the ids of timers that are still pending.
+  let mut timer_id = deno_isolate.next_timer_id;
+  while timer_id == 0 || deno_isolate.pending_timers.contains_key(&timer_id) {
+    timer_id = timer_id.wrapping_add(1);
+  }
+  deno_isolate.next_timer_id = timer_id.wrapping_add(1);
+  let mut control = timer_id.to_le_bytes().to_vec();
+  control.extend_from_slice(&delay.to_le_bytes());
+
+  // The timer op id is always registered, so the only way this fails is a
+  // sync response, which would never fire the timer. The callback is only
+  // kept once the op has been queued.
+  if deno_isolate
+    .dispatch_op(scope, op_id, &control, None)
+    .is_some()
+  {
+    let msg = v8::String::new(scope, "The timer op must be async").unwrap();
+    let exception = v8::Exception::error(scope, msg);
+    scope.isolate().throw_exception(exception);
+    return;
+  }
+
+  let mut handle = v8::Global::<v8::Function>::new();
+  handle.set(scope, callback);
+  deno_isolate.pending_timers.insert(
+    timer_id,
+    PendingTimer {
+      deadline: Instant::now() + Duration::from_millis(delay.into()),
+      callback: handle,
+    },
+  );
+  rv.set(v8::Integer::new_from_unsigned(scope, timer_id).into());
+}
+
+fn clear_timeout(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+  let timer_id = match v8::Local::<v8::Uint32>::try_from(args.get(0)) {
+    Ok(timer_id) => timer_id.value(),
+    // Like in browsers, clearing an invalid id does nothing.
+    Err(_) => return,
+  };
+  if let Some(mut timer) = deno_isolate.pending_timers.remove(&timer_id) {
+    timer.callback.reset(scope);
+  }
+}
+
+/// Returns the fast channel of a realm and the SharedArrayBuffer over it.
+fn realm_fast_channel(
+  deno_isolate: &mut Isolate,
+  realm_id: RealmId,
+) -> (
+  &mut Option<v8::SharedRef<v8::BackingStore>>,
+  &mut v8::Global<v8::SharedArrayBuffer>,
+) {
+  if realm_id == MAIN_REALM {
+    (
+      &mut deno_isolate.fast_channel,
+      &mut deno_isolate.fast_channel_ab,
+    )
+  } else {
+    let realm = &mut deno_isolate.realms[realm_id - 1];
+    (&mut realm.fast_channel, &mut realm.fast_channel_ab)
+  }
+}
+
+// Returns the SharedArrayBuffer set up with `Isolate::setup_fast_channel()`
+// for the calling realm, or undefined.
+fn get_fast_channel(
+  scope: v8::FunctionCallbackScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+
+  let realm_id = match deno_isolate.current_realm(scope) {
+    Some(realm_id) => realm_id,
+    None => return,
+  };
+  let (fast_channel, fast_channel_ab) =
+    realm_fast_channel(deno_isolate, realm_id);
+  let backing_store = match fast_channel.as_mut() {
+    Some(backing_store) => backing_store,
+    None => return,
+  };
+  if fast_channel_ab.is_empty() {
+    let ab = v8::SharedArrayBuffer::with_backing_store(scope, backing_store);
+    fast_channel_ab.set(scope, ab);
+  }
+  rv.set(fast_channel_ab.get(scope).unwrap().into());
+}
+
+// Dispatches an op whose control buffer is the first `args[1]` bytes of the
+// fast channel. A sync response is written to the start of the channel and
+// its length is returned; async ops return null.
+fn send_fast(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+
+  let op_id = match v8::Local::<v8::Uint32>::try_from(args.get(0)) {
+    Ok(op_id) => op_id.value() as OpId,
+    Err(_) => {
+      let msg = v8::String::new(scope, "Invalid op id").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  // Isolates without realms don't pay for looking up the calling realm.
+  let realm_id = if deno_isolate.realms.is_empty() {
+    Some(MAIN_REALM)
+  } else {
+    deno_isolate.current_realm(scope)
+  };
+  let channel = match realm_id
+    .and_then(|realm_id| realm_fast_channel(deno_isolate, realm_id).0.clone())
+  {
+    Some(backing_store) => backing_store,
+    None => {
+      let msg =
+        v8::String::new(scope, "No fast channel has been set up").unwrap();
+      let exception = v8::Exception::error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  // JS views share this memory, so it is only accessed through raw pointers
+  // and the control buffer is copied out before it is passed to the op.
+  let (data, channel_len) = {
+    let backing_store = unsafe { &*channel.get() };
+    (backing_store.data() as *mut u8, backing_store.byte_length())
+  };
+  let control_len = match v8::Local::<v8::Uint32>::try_from(args.get(1)) {
+    Ok(len) if len.value() as usize <= channel_len => len.value() as usize,
+    _ => {
+      let msg = v8::String::new(scope, "Invalid control length").unwrap();
+      let exception = v8::Exception::range_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+
+  let mut control = vec![0u8; control_len];
+  unsafe {
+    std::ptr::copy_nonoverlapping(data, control.as_mut_ptr(), control_len)
+  };
+
+  let maybe_response = deno_isolate.dispatch_op(scope, op_id, &control, None);
+
+  let response: Box<[u8]> = match maybe_response {
+    Some((_op_id, SyncResponse::Buf(buf))) => buf,
+    Some((_op_id, SyncResponse::I32(value))) => Box::new(value.to_le_bytes()),
+    None => {
+      rv.set(v8::null(scope).into());
+      return;
+    }
+  };
+  if response.len() > channel_len {
+    let msg =
+      v8::String::new(scope, "Response does not fit the fast channel").unwrap();
+    let exception = v8::Exception::range_error(scope, msg);
+    scope.isolate().throw_exception(exception);
+    return;
+  }
+  unsafe {
+    std::ptr::copy_nonoverlapping(response.as_ptr(), data, response.len())
+  };
+  rv.set(v8::Integer::new_from_unsigned(scope, response.len() as u32).into());
+}
+
+fn dispatch_console(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+  let context = scope.get_current_context().unwrap();
+
+  let op_id = match deno_isolate.console_op_id() {
+    Some(op_id) => op_id,
+    None => {
+      let msg =
+        v8::String::new(scope, "No console op has been registered").unwrap();
+      let exception = v8::Exception::error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  let method = match v8::Local::<v8::String>::try_from(args.get(0)) {
+    Ok(method) => method.to_rust_string_lossy(scope),
+    Err(_) => {
+      let msg = v8::String::new(scope, "Method must be a string").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  if !args.get(1).is_array() {
+    let msg = v8::String::new(scope, "Arguments must be an array").unwrap();
+    let exception = v8::Exception::type_error(scope, msg);
+    scope.isolate().throw_exception(exception);
+    return;
+  }
+  // A TypeError has been thrown if this fails, e.g. for cyclic arguments.
+  let json = match v8::json::stringify(context, args.get(1)) {
+    Some(json) => json.to_rust_string_lossy(scope),
+    None => return,
+  };
+
+  let mut control = (method.len() as u32).to_le_bytes().to_vec();
+  control.extend_from_slice(method.as_bytes());
+  control.extend_from_slice(json.as_bytes());
+  deno_isolate.dispatch_op(scope, op_id, &control, None);
+}
+
+/// Throws a TypeError and returns false if the ArrayBuffer behind `view` has
+/// been detached, e.g. because it was transferred.
+fn check_not_detached<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  view: v8::Local<v8::ArrayBufferView>,
+) -> bool {
+  match view.buffer() {
+    Some(buffer) if !buffer.was_detached() => true,
+    _ => {
+      let msg =
+        v8::String::new(scope, "Cannot send detached ArrayBuffer").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      false
     }
   }
 }
@@ -518,7 +1344,7 @@ fn eval_context(
   let name =
     v8::String::new(scope, url.as_ref().map_or("<unknown>", Url::as_str))
       .unwrap();
-  let origin = script_origin(scope, name);
+  let origin = script_origin(scope, name, None, next_script_id());
   let maybe_script = v8::Script::compile(scope, context, source, Some(&origin));
 
   if maybe_script.is_none() {
@@ -531,24 +1357,7 @@ fn eval_context(
       v8::null(scope).into(),
     );
 
-    let errinfo_obj = v8::Object::new(scope);
-    errinfo_obj.set(
-      context,
-      v8::String::new(scope, "isCompileError").unwrap().into(),
-      v8::Boolean::new(scope, true).into(),
-    );
-
-    errinfo_obj.set(
-      context,
-      v8::String::new(scope, "isNativeError").unwrap().into(),
-      v8::Boolean::new(scope, exception.is_native_error()).into(),
-    );
-
-    errinfo_obj.set(
-      context,
-      v8::String::new(scope, "thrown").unwrap().into(),
-      exception,
-    );
+    let errinfo_obj = eval_error_info(scope, context, exception, true);
 
     output.set(
       context,
@@ -572,30 +1381,7 @@ fn eval_context(
       v8::null(scope).into(),
     );
 
-    let errinfo_obj = v8::Object::new(scope);
-    errinfo_obj.set(
-      context,
-      v8::String::new(scope, "isCompileError").unwrap().into(),
-      v8::Boolean::new(scope, false).into(),
-    );
-
-    let is_native_error = if exception.is_native_error() {
-      v8::Boolean::new(scope, true)
-    } else {
-      v8::Boolean::new(scope, false)
-    };
-
-    errinfo_obj.set(
-      context,
-      v8::String::new(scope, "isNativeError").unwrap().into(),
-      is_native_error.into(),
-    );
-
-    errinfo_obj.set(
-      context,
-      v8::String::new(scope, "thrown").unwrap().into(),
-      exception,
-    );
+    let errinfo_obj = eval_error_info(scope, context, exception, false);
 
     output.set(
       context,
@@ -616,6 +1402,107 @@ fn eval_context(
   rv.set(output.into());
 }
 
+// Like `evalContext()`, but the result is returned as a JSON string, for
+// hosts that only need the value as data. Failing to serialize the result is
+// reported like an exception thrown by the script.
+fn eval_to_json(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+  assert!(!deno_isolate.global_context.is_empty());
+  let context = deno_isolate.global_context.get(scope).unwrap();
+
+  let source = match v8::Local::<v8::String>::try_from(args.get(0)) {
+    Ok(s) => s,
+    Err(_) => {
+      let msg = v8::String::new(scope, "Invalid argument").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+
+  /*
+   output[0] = JSON string | null
+   output[1] = ErrorInfo | null
+
+   output[0] is also null if the result has no JSON representation, e.g.
+   because it is undefined or a function.
+  */
+  let output = v8::Array::new(scope, 2);
+  let mut try_catch = v8::TryCatch::new(scope);
+  let tc = try_catch.enter();
+  let name = v8::String::new(scope, "<unknown>").unwrap();
+  let origin = script_origin(scope, name, None, next_script_id());
+
+  let (json, errinfo) =
+    match v8::Script::compile(scope, context, source, Some(&origin)) {
+      None => {
+        let exception = tc.exception().unwrap();
+        (None, Some(eval_error_info(scope, context, exception, true)))
+      }
+      Some(mut script) => match script.run(scope, context).and_then(|result| {
+        if is_json_undefined(result) {
+          Some(None)
+        } else {
+          v8::json::stringify(context, result).map(Some)
+        }
+      }) {
+        Some(json) => (json, None),
+        None => {
+          let exception = tc.exception().unwrap();
+          (
+            None,
+            Some(eval_error_info(scope, context, exception, false)),
+          )
+        }
+      },
+    };
+
+  let json: v8::Local<v8::Value> = match json {
+    Some(json) => json.into(),
+    None => v8::null(scope).into(),
+  };
+  let errinfo: v8::Local<v8::Value> = match errinfo {
+    Some(errinfo) => errinfo.into(),
+    None => v8::null(scope).into(),
+  };
+  output.set(context, v8::Integer::new(scope, 0).into(), json);
+  output.set(context, v8::Integer::new(scope, 1).into(), errinfo);
+  rv.set(output.into());
+}
+
+/// Returns true for the values that `JSON.stringify()` maps to undefined
+/// rather than to a JSON string. `v8::json::stringify()` returns the string
+/// "undefined" for them, which is not JSON.
+pub(crate) fn is_json_undefined(value: v8::Local<v8::Value>) -> bool {
+  value.is_undefined() || value.is_function() || value.is_symbol()
+}
+
+fn eval_error_info<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  context: v8::Local<v8::Context>,
+  exception: v8::Local<v8::Value>,
+  is_compile_error: bool,
+) -> v8::Local<'s, v8::Object> {
+  let errinfo_obj = v8::Object::new(scope);
+  errinfo_obj.set(
+    context,
+    v8_key(scope, "isCompileError").into(),
+    v8::Boolean::new(scope, is_compile_error).into(),
+  );
+  errinfo_obj.set(
+    context,
+    v8_key(scope, "isNativeError").into(),
+    v8::Boolean::new(scope, exception.is_native_error()).into(),
+  );
+  errinfo_obj.set(context, v8_key(scope, "thrown").into(), exception);
+  errinfo_obj
+}
+
 fn format_error(
   scope: v8::FunctionCallbackScope,
   args: v8::FunctionCallbackArguments,
@@ -689,6 +1576,216 @@ fn decode(
   rv.set(text_str.into())
 }
 
+// Parses JSON with V8's parser, which unlike the global `JSON` can't be
+// tampered with by user code. Invalid JSON throws a SyntaxError.
+fn json_parse(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let context = scope.get_current_context().unwrap();
+  let json = match v8::Local::<v8::String>::try_from(args.get(0)) {
+    Ok(json) => json,
+    Err(_) => {
+      let msg = v8::String::new(scope, "Invalid argument").unwrap();
+      let exception = v8::Exception::type_error(scope, msg);
+      scope.isolate().throw_exception(exception);
+      return;
+    }
+  };
+  // A SyntaxError has been thrown if this fails.
+  if let Some(value) = v8::json::parse(context, json) {
+    rv.set(value);
+  }
+}
+
+// The counterpart of `json_parse()`. Like `JSON.stringify()`, returns
+// undefined for values that have no JSON representation, and throws for
+// cyclic values.
+fn json_stringify(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let context = scope.get_current_context().unwrap();
+  let value = args.get(0);
+  if is_json_undefined(value) {
+    return;
+  }
+  // A TypeError has been thrown if this fails.
+  if let Some(json) = v8::json::stringify(context, value) {
+    rv.set(json.into());
+  }
+}
+
+// Returns the milliseconds elapsed since the isolate was created, read from
+// a monotonic clock.
+fn now(
+  scope: v8::FunctionCallbackScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let deno_isolate: &mut Isolate =
+    unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+  let elapsed = deno_isolate.start_time.elapsed();
+  let ms = elapsed.as_secs_f64() * 1000.0;
+  rv.set(v8::Number::new(scope, ms).into());
+}
+
+/// How deeply `Deno.core.inspect()` formats nested objects by default.
+const DEFAULT_INSPECT_DEPTH: usize = 2;
+
+// Formats a value for display, like Node's util.inspect(), e.g.
+// `{ a: 1, b: [ "c", [Object] ] }`. Takes an optional `{ depth }` options
+// object.
+fn inspect(
+  scope: v8::FunctionCallbackScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let context = scope.get_current_context().unwrap();
+
+  let mut depth = DEFAULT_INSPECT_DEPTH;
+  if let Ok(options) = v8::Local::<v8::Object>::try_from(args.get(1)) {
+    let depth_key = v8_key(scope, "depth");
+    let maybe_depth = options
+      .get(scope, context, depth_key.into())
+      .and_then(|d| v8::Local::<v8::Integer>::try_from(d).ok());
+    if let Some(d) = maybe_depth {
+      depth = d.value().max(0) as usize;
+    }
+  }
+
+  let mut seen = vec![];
+  // On None, an exception thrown by a getter propagates to the caller.
+  if let Some(output) =
+    inspect_value(scope, context, args.get(0), 0, depth, &mut seen)
+  {
+    rv.set(v8::String::new(scope, &output).unwrap().into());
+  }
+}
+
+// `seen` holds the objects currently being formatted, i.e. the ancestors of
+// `value`, to detect cycles. Returns None if a getter or a conversion threw.
+fn inspect_value<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  context: v8::Local<v8::Context>,
+  value: v8::Local<'s, v8::Value>,
+  level: usize,
+  depth: usize,
+  seen: &mut Vec<v8::Local<'s, v8::Value>>,
+) -> Option<String> {
+  if value.is_string() {
+    let s = value.to_string(scope)?.to_rust_string_lossy(scope);
+    return Some(if level == 0 { s } else { format!("{:?}", s) });
+  }
+  if let Ok(function) = v8::Local::<v8::Function>::try_from(value) {
+    let name = function.get_name(scope).to_rust_string_lossy(scope);
+    return Some(if name.is_empty() {
+      "[Function]".to_string()
+    } else {
+      format!("[Function: {}]", name)
+    });
+  }
+  let object = match v8::Local::<v8::Object>::try_from(value) {
+    Ok(object) => object,
+    // Primitives. Unlike to_string(), to_detail_string() doesn't throw for
+    // symbols.
+    Err(_) => {
+      return Some(value.to_detail_string(scope)?.to_rust_string_lossy(scope))
+    }
+  };
+
+  // Identity hashes aren't unique, so compare the objects themselves.
+  if seen.iter().any(|ancestor| ancestor.strict_equals(value)) {
+    return Some("[Circular]".to_string());
+  }
+
+  if let Ok(array) = v8::Local::<v8::Array>::try_from(value) {
+    if level > depth {
+      return Some("[Array]".to_string());
+    }
+    seen.push(value);
+    let mut items = vec![];
+    for i in 0..array.length() {
+      let item = array.get_index(scope, context, i)?;
+      items.push(inspect_value(scope, context, item, level + 1, depth, seen)?);
+    }
+    seen.pop();
+    return Some(format_entries("", "[", &items, "]"));
+  }
+
+  if let Ok(map) = v8::Local::<v8::Map>::try_from(value) {
+    if level > depth {
+      return Some("[Map]".to_string());
+    }
+    seen.push(value);
+    // Keys and values are interleaved.
+    let entries = map.as_array(scope);
+    let mut items = vec![];
+    for i in (0..entries.length()).step_by(2) {
+      let key = entries.get_index(scope, context, i)?;
+      let key = inspect_value(scope, context, key, level + 1, depth, seen)?;
+      let value = entries.get_index(scope, context, i + 1)?;
+      let value = inspect_value(scope, context, value, level + 1, depth, seen)?;
+      items.push(format!("{} => {}", key, value));
+    }
+    seen.pop();
+    return Some(format_entries("Map ", "{", &items, "}"));
+  }
+
+  if let Ok(set) = v8::Local::<v8::Set>::try_from(value) {
+    if level > depth {
+      return Some("[Set]".to_string());
+    }
+    seen.push(value);
+    let values = set.as_array(scope);
+    let mut items = vec![];
+    for i in 0..values.length() {
+      let value = values.get_index(scope, context, i)?;
+      items.push(inspect_value(
+        scope,
+        context,
+        value,
+        level + 1,
+        depth,
+        seen,
+      )?);
+    }
+    seen.pop();
+    return Some(format_entries("Set ", "{", &items, "}"));
+  }
+
+  if level > depth {
+    return Some("[Object]".to_string());
+  }
+  seen.push(value);
+  let keys = object.get_own_property_names(scope, context)?;
+  let mut items = vec![];
+  for i in 0..keys.length() {
+    let key = keys.get_index(scope, context, i)?;
+    let value = object.get(scope, context, key)?;
+    let key = key.to_string(scope)?.to_rust_string_lossy(scope);
+    let value = inspect_value(scope, context, value, level + 1, depth, seen)?;
+    items.push(format!("{}: {}", key, value));
+  }
+  seen.pop();
+  Some(format_entries("", "{", &items, "}"))
+}
+
+fn format_entries(
+  prefix: &str,
+  open: &str,
+  items: &[String],
+  close: &str,
+) -> String {
+  if items.is_empty() {
+    format!("{}{}{}", prefix, open, close)
+  } else {
+    format!("{}{} {} {}", prefix, open, items.join(", "), close)
+  }
+}
+
 fn queue_microtask(
   scope: v8::FunctionCallbackScope,
   args: v8::FunctionCallbackArguments,
@@ -713,17 +1810,26 @@ fn shared_getter(
   let deno_isolate: &mut Isolate =
     unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
 
+  // Each realm has its own shared queue. Other contexts have none.
+  let (shared, shared_ab) = match deno_isolate.current_realm(scope) {
+    Some(MAIN_REALM) => (&mut deno_isolate.shared, &mut deno_isolate.shared_ab),
+    Some(realm_id) => {
+      let realm = &mut deno_isolate.realms[realm_id - 1];
+      (&mut realm.shared, &mut realm.shared_ab)
+    }
+    None => return,
+  };
+
   // Lazily initialize the persistent external ArrayBuffer.
-  if deno_isolate.shared_ab.is_empty() {
+  if shared_ab.is_empty() {
     let ab = v8::SharedArrayBuffer::with_backing_store(
       scope,
-      deno_isolate.shared.get_backing_store(),
+      shared.get_backing_store(),
     );
-    deno_isolate.shared_ab.set(scope, ab);
+    shared_ab.set(scope, ab);
   }
 
-  let shared_ab = deno_isolate.shared_ab.get(scope).unwrap();
-  rv.set(shared_ab.into());
+  rv.set(shared_ab.get(scope).unwrap().into());
 }
 
 pub fn module_resolve_callback<'s>(
@@ -738,7 +1844,10 @@ pub fn module_resolve_callback<'s>(
   let deno_isolate: &mut EsIsolate =
     unsafe { &mut *(scope.isolate().get_data(1) as *mut EsIsolate) };
 
-  let referrer_id = referrer.get_identity_hash();
+  let referrer_id = deno_isolate
+    .modules
+    .get_id_by_identity_hash(referrer.get_identity_hash())
+    .expect("ModuleInfo not found");
   let referrer_name = deno_isolate
     .modules
     .get_info(referrer_id)
@@ -749,6 +1858,15 @@ pub fn module_resolve_callback<'s>(
 
   let specifier_str = specifier.to_rust_string_lossy(scope);
 
+  let cache_key = (referrer_id, specifier_str.clone());
+  if let Some(&id) = deno_isolate.resolve_cache.get(&cache_key) {
+    return deno_isolate
+      .modules
+      .get_info(id)
+      .and_then(|i| i.handle.get(scope))
+      .map(|m| scope.escape(m));
+  }
+
   for i in 0..len_ {
     let req = referrer.get_module_request(i);
     let req_str = req.to_rust_string_lossy(scope);
@@ -758,6 +1876,8 @@ pub fn module_resolve_callback<'s>(
       let maybe_info = deno_isolate.modules.get_info(id);
 
       if maybe_info.is_none() {
+        deno_isolate.last_resolve_error =
+          Some((req_str.clone(), referrer_name.clone()));
         let msg = format!(
           "Cannot resolve module \"{}\" from \"{}\"",
           req_str, referrer_name
@@ -767,6 +1887,7 @@ pub fn module_resolve_callback<'s>(
         break;
       }
 
+      deno_isolate.resolve_cache.insert(cache_key, id);
       return maybe_info
         .and_then(|i| i.handle.get(scope))
         .map(|m| scope.escape(m));
@@ -776,6 +1897,27 @@ pub fn module_resolve_callback<'s>(
   None
 }
 
+/// Used to instantiate modules run by `Isolate::execute_module()`, which
+/// can't import other modules.
+pub fn no_imports_resolve_callback<'s>(
+  context: v8::Local<'s, v8::Context>,
+  specifier: v8::Local<'s, v8::String>,
+  _referrer: v8::Local<'s, v8::Module>,
+) -> Option<v8::Local<'s, v8::Module>> {
+  let mut cbs = v8::CallbackScope::new(context);
+  let mut hs = v8::HandleScope::new(cbs.enter());
+  let scope = hs.enter();
+
+  let msg = format!(
+    "Cannot import \"{}\" from a module that was executed directly",
+    specifier.to_rust_string_lossy(scope)
+  );
+  let msg = v8::String::new(scope, &msg).unwrap();
+  let exception = v8::Exception::type_error(scope, msg);
+  scope.isolate().throw_exception(exception);
+  None
+}
+
 // Returns promise details or throw TypeError, if argument passed isn't a Promise.
 // Promise details is a two elements array.
 // promise_details = [State, Result]
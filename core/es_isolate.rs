@@ -18,6 +18,7 @@ use futures::task::AtomicWaker;
 use futures::Future;
 use libc::c_void;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ops::{Deref, DerefMut};
 use std::option::Option;
 use std::pin::Pin;
@@ -28,10 +29,14 @@ use std::task::Poll;
 use crate::isolate::attach_handle_to_error;
 use crate::isolate::exception_to_err_result;
 use crate::isolate::Isolate;
+use crate::isolate::IsolateBuilder;
+use crate::isolate::JSErrorCreateFn;
 use crate::isolate::StartupData;
 use crate::module_specifier::ModuleSpecifier;
 use crate::modules::LoadState;
+use crate::modules::ModuleInfo;
 use crate::modules::ModuleLoader;
+use crate::modules::ModuleManifest;
 use crate::modules::ModuleSource;
 use crate::modules::Modules;
 use crate::modules::RecursiveModuleLoad;
@@ -39,6 +44,19 @@ use crate::modules::RecursiveModuleLoad;
 pub type ModuleId = i32;
 pub type DynImportId = i32;
 
+/// Reports a module that is missing or can't be evaluated in its current
+/// state, e.g. because it hasn't been instantiated, as an `Error` rather than
+/// aborting the process.
+fn module_status_error<'s, T>(
+  scope: &mut impl v8::ToLocal<'s>,
+  message: &str,
+  js_error_create_fn: &JSErrorCreateFn,
+) -> Result<T, ErrBox> {
+  let message = v8::String::new(scope, message).unwrap();
+  let exception = v8::Exception::error(scope, message);
+  exception_to_err_result(scope, exception, js_error_create_fn)
+}
+
 /// More specialized version of `Isolate` that provides loading
 /// and execution of ES Modules.
 ///
@@ -47,14 +65,29 @@ pub type DynImportId = i32;
 /// loading of modules can be customized by the implementor.
 pub struct EsIsolate {
   core_isolate: Box<Isolate>,
-  loader: Rc<dyn ModuleLoader>,
+  pub(crate) loader: Rc<dyn ModuleLoader>,
   pub modules: Modules,
   pub(crate) next_dyn_import_id: DynImportId,
   pub(crate) dyn_import_map:
     HashMap<DynImportId, v8::Global<v8::PromiseResolver>>,
+  pending_mod_evaluates: HashMap<ModuleId, v8::Global<v8::Promise>>,
 
   pending_dyn_imports: FuturesUnordered<StreamFuture<RecursiveModuleLoad>>,
   waker: AtomicWaker,
+  /// The `(specifier, referrer)` of the last import that could not be
+  /// resolved during instantiation.
+  pub(crate) last_resolve_error: Option<(String, String)>,
+  /// The chain of imports from the instantiated module to the specifier in
+  /// `last_resolve_error`.
+  last_resolve_error_path: Vec<String>,
+  /// Options set with `set_host_defined_options()`, keyed by resource name.
+  pub(crate) host_defined_options: HashMap<String, Vec<String>>,
+  /// Modules resolved by the running `mod_instantiate()`, keyed by referrer
+  /// and specifier, so that the loader is asked only once per pair even when
+  /// V8 resolves it again. Cleared when `mod_instantiate()` returns, since
+  /// modules may be removed or registered before the next call.
+  pub(crate) resolve_cache: HashMap<(ModuleId, String), ModuleId>,
+  on_module_evaluated: Option<Box<dyn FnMut(ModuleId, bool)>>,
 }
 
 impl Deref for EsIsolate {
@@ -77,7 +110,19 @@ impl EsIsolate {
     startup_data: StartupData,
     will_snapshot: bool,
   ) -> Box<Self> {
-    let mut core_isolate = Isolate::new(startup_data, will_snapshot);
+    let builder = IsolateBuilder::new()
+      .startup_data(startup_data)
+      .will_snapshot(will_snapshot);
+    Self::with_builder(loader, builder)
+  }
+
+  /// Same as `EsIsolate::new()`, but the underlying `Isolate` is configured
+  /// by the given `IsolateBuilder`.
+  pub fn with_builder(
+    loader: Rc<dyn ModuleLoader>,
+    builder: IsolateBuilder,
+  ) -> Box<Self> {
+    let mut core_isolate = builder.build();
     {
       let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
       v8_isolate.set_host_initialize_import_meta_object_callback(
@@ -94,8 +139,14 @@ impl EsIsolate {
       core_isolate,
       next_dyn_import_id: 0,
       dyn_import_map: HashMap::new(),
+      pending_mod_evaluates: HashMap::new(),
       pending_dyn_imports: FuturesUnordered::new(),
       waker: AtomicWaker::new(),
+      last_resolve_error: None,
+      last_resolve_error_path: vec![],
+      host_defined_options: HashMap::new(),
+      resolve_cache: HashMap::new(),
+      on_module_evaluated: None,
     };
 
     let mut boxed_es_isolate = Box::new(es_isolate);
@@ -118,6 +169,19 @@ impl EsIsolate {
     main: bool,
     name: &str,
     source: &str,
+  ) -> Result<ModuleId, ErrBox> {
+    self.mod_new_with_offset(main, name, source, 0, 0)
+  }
+
+  /// Same as `mod_new()`, but `line_offset` and `column_offset` give where
+  /// `source` starts in the file called `name`.
+  fn mod_new_with_offset(
+    &mut self,
+    main: bool,
+    name: &str,
+    source: &str,
+    line_offset: i32,
+    column_offset: i32,
   ) -> Result<ModuleId, ErrBox> {
     let core_isolate = &mut self.core_isolate;
     let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
@@ -133,7 +197,15 @@ impl EsIsolate {
     let name_str = v8::String::new(scope, name).unwrap();
     let source_str = v8::String::new(scope, source).unwrap();
 
-    let origin = bindings::module_origin(scope, name_str);
+    let source_map_url = bindings::source_map_url_from_source(source);
+    let origin = bindings::module_origin_with_offset(
+      scope,
+      name_str,
+      source_map_url,
+      bindings::next_script_id(),
+      line_offset,
+      column_offset,
+    );
     let source = v8::script_compiler::Source::new(source_str, &origin);
 
     let mut try_catch = v8::TryCatch::new(scope);
@@ -141,17 +213,25 @@ impl EsIsolate {
 
     let maybe_module = v8::script_compiler::compile_module(scope, source);
 
-    if tc.has_caught() {
-      assert!(maybe_module.is_none());
-      return exception_to_err_result(
-        scope,
-        tc.exception().unwrap(),
-        js_error_create_fn,
-      );
-    }
-
-    let module = maybe_module.unwrap();
-    let id = module.get_identity_hash();
+    let module = match maybe_module {
+      Some(module) if !tc.has_caught() => module,
+      _ => {
+        // V8 may fail to compile a module without throwing, e.g. when
+        // execution is being terminated.
+        let exception = match tc.exception() {
+          Some(exception) => exception,
+          None => {
+            let message = v8::String::new(
+              scope,
+              &format!("Failed to compile module \"{}\"", name),
+            )
+            .unwrap();
+            v8::Exception::error(scope, message)
+          }
+        };
+        return exception_to_err_result(scope, exception, js_error_create_fn);
+      }
+    };
 
     let mut import_specifiers: Vec<ModuleSpecifier> = vec![];
     for i in 0..module.get_module_requests_length() {
@@ -164,9 +244,13 @@ impl EsIsolate {
 
     let mut handle = v8::Global::<v8::Module>::new();
     handle.set(scope, module);
-    self
-      .modules
-      .register(id, name, main, handle, import_specifiers);
+    let id = self.modules.register(
+      name,
+      main,
+      handle,
+      module.get_identity_hash(),
+      import_specifiers,
+    );
     Ok(id)
   }
 
@@ -176,6 +260,12 @@ impl EsIsolate {
   /// the V8 exception. By default this type is JSError, however it may be a
   /// different type if Isolate::set_js_error_create_fn() has been used.
   fn mod_instantiate(&mut self, id: ModuleId) -> Result<(), ErrBox> {
+    let result = self.instantiate_module(id);
+    self.resolve_cache.clear();
+    result
+  }
+
+  fn instantiate_module(&mut self, id: ModuleId) -> Result<(), ErrBox> {
     let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
     let js_error_create_fn = &*self.core_isolate.js_error_create_fn;
 
@@ -207,16 +297,50 @@ impl EsIsolate {
     let result =
       module.instantiate_module(context, bindings::module_resolve_callback);
     match result {
-      Some(_) => Ok(()),
+      Some(_) => {
+        self.last_resolve_error = None;
+        self.last_resolve_error_path.clear();
+        Ok(())
+      }
       None => {
+        self.last_resolve_error_path = match self.last_resolve_error {
+          Some((ref specifier, ref referrer)) => {
+            let mut path =
+              self.modules.import_path(id, referrer).unwrap_or_default();
+            path.push(specifier.clone());
+            path
+          }
+          None => vec![],
+        };
         let exception = tc.exception().unwrap();
         exception_to_err_result(scope, exception, js_error_create_fn)
       }
     }
   }
 
+  /// Registers a callback that is invoked with the module id and whether
+  /// evaluation succeeded whenever a module finishes evaluating, so that
+  /// loaders can trigger follow-up work without polling `mod_status()`.
+  ///
+  /// The callback fires exactly once per evaluation: from `mod_evaluate()`,
+  /// or, when the module waits for top-level await, from the
+  /// `poll_mod_evaluate()` call that observes the evaluation settle.
+  /// Evaluating an already evaluated module doesn't invoke it again.
+  pub fn set_on_module_evaluated(
+    &mut self,
+    f: impl FnMut(ModuleId, bool) + 'static,
+  ) {
+    self.on_module_evaluated = Some(Box::new(f));
+  }
+
   /// Evaluates an already instantiated ES module.
   ///
+  /// With top-level await, this returns as soon as the module body suspends,
+  /// so the module may still be doing asynchronous work. Use
+  /// `EsIsolate::poll_mod_evaluate()` to find out when it actually finishes.
+  /// The isolate must keep being polled in the meantime so that pending ops
+  /// can resolve.
+  ///
   /// ErrBox can be downcast to a type that exposes additional information about
   /// the V8 exception. By default this type is JSError, however it may be a
   /// different type if Isolate::set_js_error_create_fn() has been used.
@@ -232,33 +356,334 @@ impl EsIsolate {
     let mut cs = v8::ContextScope::new(scope, context);
     let scope = cs.enter();
 
-    let info = self.modules.get_info(id).expect("ModuleInfo not found");
-    let mut module = info.handle.get(scope).expect("Empty module handle");
-    let mut status = module.get_status();
-
-    if status == v8::ModuleStatus::Instantiated {
-      let ok = module.evaluate(scope, context).is_some();
-      // Update status after evaluating.
-      status = module.get_status();
-      if ok {
-        assert!(
-          status == v8::ModuleStatus::Evaluated
-            || status == v8::ModuleStatus::Errored
-        );
-      } else {
-        assert!(status == v8::ModuleStatus::Errored);
+    let mut module = match self.modules.get_info(id) {
+      Some(info) => info.handle.get(scope).expect("Empty module handle"),
+      None => {
+        let message = format!("Module id {} not found", id);
+        return module_status_error(scope, &message, js_error_create_fn);
+      }
+    };
+
+    let evaluated = module.get_status() == v8::ModuleStatus::Instantiated;
+    if evaluated {
+      let maybe_value = module.evaluate(scope, context);
+      // With top-level await enabled, V8 returns a promise that settles once
+      // the evaluation of the module graph has completed.
+      if let Some(mut promise) =
+        maybe_value.and_then(|v| v8::Local::<v8::Promise>::try_from(v).ok())
+      {
+        if promise.state() == v8::PromiseState::Pending {
+          let mut promise_handle = v8::Global::<v8::Promise>::new();
+          promise_handle.set(scope, promise);
+          self.pending_mod_evaluates.insert(id, promise_handle);
+          return Ok(());
+        }
       }
     }
 
-    match status {
+    let result = match module.get_status() {
       v8::ModuleStatus::Evaluated => Ok(()),
       v8::ModuleStatus::Errored => {
         let exception = module.get_exception();
         exception_to_err_result(scope, exception, js_error_create_fn)
           .map_err(|err| attach_handle_to_error(scope, err, exception))
       }
-      other => panic!("Unexpected module status {:?}", other),
+      other => {
+        let message = format!(
+          "Unexpected status {:?} of module \"{}\"",
+          other,
+          self.modules.get_name(id).unwrap()
+        );
+        module_status_error(scope, &message, js_error_create_fn)
+      }
+    };
+    if evaluated {
+      if let Some(cb) = self.on_module_evaluated.as_mut() {
+        cb(id, result.is_ok());
+      }
+    }
+    result
+  }
+
+  /// Checks whether an evaluation started with `EsIsolate::mod_evaluate()`
+  /// has completed, including its top-level await. Returns
+  /// `Poll::Ready(Ok(()))` for modules that have no evaluation pending.
+  pub fn poll_mod_evaluate(
+    &mut self,
+    id: ModuleId,
+  ) -> Poll<Result<(), ErrBox>> {
+    let mut promise_handle = match self.pending_mod_evaluates.remove(&id) {
+      Some(handle) => handle,
+      None => return Poll::Ready(Ok(())),
+    };
+
+    let core_isolate = &mut self.core_isolate;
+    let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+    let js_error_create_fn = &*core_isolate.js_error_create_fn;
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!core_isolate.global_context.is_empty());
+    let context = core_isolate.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let mut promise = promise_handle.get(scope).unwrap();
+    let result = match promise.state() {
+      v8::PromiseState::Pending => {
+        self.pending_mod_evaluates.insert(id, promise_handle);
+        return Poll::Pending;
+      }
+      v8::PromiseState::Fulfilled => {
+        promise_handle.reset(scope);
+        Ok(())
+      }
+      v8::PromiseState::Rejected => {
+        promise_handle.reset(scope);
+        // The rejection is reported here, so it must not also surface as an
+        // unhandled promise rejection when the isolate is polled.
+        let promise_id = promise.get_identity_hash();
+        if let Some(mut handle) =
+          core_isolate.pending_promise_exceptions.remove(&promise_id)
+        {
+          handle.reset(scope);
+        }
+        let exception = promise.result(scope);
+        exception_to_err_result(scope, exception, js_error_create_fn)
+          .map_err(|err| attach_handle_to_error(scope, err, exception))
+      }
+    };
+    if let Some(cb) = self.on_module_evaluated.as_mut() {
+      cb(id, result.is_ok());
+    }
+    Poll::Ready(result)
+  }
+
+  /// Unregisters a module and releases the V8 handle that keeps it alive.
+  ///
+  /// Note that V8 only frees the module once nothing references it anymore.
+  /// In particular, the modules that import it keep it alive until they are
+  /// removed as well.
+  pub fn mod_remove(&mut self, id: ModuleId) {
+    self.take_module(id);
+  }
+
+  /// Unregisters all modules and releases their V8 handles. Useful for hosts
+  /// that reload their module graph, which would otherwise accumulate
+  /// modules for the lifetime of the isolate.
+  pub fn mod_clear(&mut self) {
+    self.take_all_modules();
+  }
+
+  /// Unregisters a module and returns its info with the handle already reset.
+  fn take_module(&mut self, id: ModuleId) -> Option<ModuleInfo> {
+    let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+
+    if let Some(mut handle) = self.pending_mod_evaluates.remove(&id) {
+      handle.reset(scope);
+    }
+    let mut info = self.modules.remove(id)?;
+    info.handle.reset(scope);
+    Some(info)
+  }
+
+  /// Unregisters all modules and returns their infos with the handles
+  /// already reset.
+  fn take_all_modules(&mut self) -> Vec<ModuleInfo> {
+    let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+
+    for (_, mut handle) in self.pending_mod_evaluates.drain() {
+      handle.reset(scope);
+    }
+    let mut infos = self.modules.clear();
+    for info in infos.iter_mut() {
+      info.handle.reset(scope);
+    }
+    infos
+  }
+
+  /// Takes a snapshot, like `Isolate::snapshot()`, and returns it together
+  /// with a manifest of the registered modules. The isolate should have been
+  /// created with will_snapshot set to true.
+  ///
+  /// V8 can't snapshot modules, so all modules are unregistered first and
+  /// only the state they left in the context is preserved. Pass the manifest
+  /// to `EsIsolate::restore_modules()` after starting from the snapshot to
+  /// load them again.
+  ///
+  /// Like `Isolate::snapshot()`, fails while a promise rejection is pending,
+  /// in which case the modules are left registered.
+  pub fn snapshot_with_manifest(
+    &mut self,
+  ) -> Result<(v8::OwnedStartupData, ModuleManifest), ErrBox> {
+    self.core_isolate.check_promise_exceptions()?;
+    let manifest = self.modules.manifest();
+    self.mod_clear();
+    {
+      let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+      let mut hs = v8::HandleScope::new(v8_isolate);
+      let scope = hs.enter();
+      for (_, mut handle) in self.dyn_import_map.drain() {
+        handle.reset(scope);
+      }
+    }
+    let snapshot = self.core_isolate.snapshot()?;
+    Ok((snapshot, manifest))
+  }
+
+  /// Loads and evaluates the main modules listed in a manifest returned by
+  /// `EsIsolate::snapshot_with_manifest()`, along with their dependencies.
+  /// Returns their ids in the order of the manifest.
+  ///
+  /// The modules are evaluated again, so top-level code with side effects
+  /// on the snapshotted context runs a second time.
+  pub async fn restore_modules(
+    &mut self,
+    manifest: &ModuleManifest,
+  ) -> Result<Vec<ModuleId>, ErrBox> {
+    let mut ids = vec![];
+    for name in &manifest.main {
+      let specifier = ModuleSpecifier::resolve_url(name)?;
+      let id = self.load_module(&specifier, None).await?;
+      self.mod_evaluate(id)?;
+      ids.push(id);
+    }
+    Ok(ids)
+  }
+
+  /// Returns the status of a registered module. This tells whether the module
+  /// still needs to be instantiated or evaluated, and whether it has errored
+  /// without having to evaluate it again. Returns `None` if `id` does not
+  /// refer to a registered module.
+  pub fn mod_status(&mut self, id: ModuleId) -> Option<v8::ModuleStatus> {
+    let v8_isolate = self.core_isolate.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+
+    let info = self.modules.get_info(id)?;
+    let module = info.handle.get(scope)?;
+    Some(module.get_status())
+  }
+
+  /// Returns the namespace object of an evaluated ES module. The namespace
+  /// object holds all exports of the module as properties.
+  pub fn get_module_namespace(
+    &mut self,
+    id: ModuleId,
+  ) -> Result<v8::Global<v8::Object>, ErrBox> {
+    let core_isolate = &mut self.core_isolate;
+    let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+    let js_error_create_fn = &*core_isolate.js_error_create_fn;
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!core_isolate.global_context.is_empty());
+    let context = core_isolate.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let mut module = match self.modules.get_info(id) {
+      Some(info) => info.handle.get(scope).expect("Empty module handle"),
+      None => {
+        let message = format!("Module id {} not found", id);
+        return module_status_error(scope, &message, js_error_create_fn);
+      }
+    };
+    if module.get_status() != v8::ModuleStatus::Evaluated {
+      let message = format!(
+        "Module namespace of \"{}\" is only available after evaluation",
+        self.modules.get_name(id).unwrap()
+      );
+      return module_status_error(scope, &message, js_error_create_fn);
     }
+
+    let module_namespace = module.get_module_namespace();
+    let module_namespace =
+      v8::Local::<v8::Object>::try_from(module_namespace).unwrap();
+    Ok(v8::Global::new_from(scope, module_namespace))
+  }
+
+  /// Reads the export called `name` off an evaluated ES module and serializes
+  /// it to JSON. Returns None if there is no such export or if its value
+  /// cannot be represented as JSON.
+  pub fn get_module_export_json(
+    &mut self,
+    id: ModuleId,
+    name: &str,
+  ) -> Result<Option<String>, ErrBox> {
+    let mut module_namespace = self.get_module_namespace(id)?;
+
+    let core_isolate = &mut self.core_isolate;
+    let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!core_isolate.global_context.is_empty());
+    let context = core_isolate.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let namespace = module_namespace.get(scope).unwrap();
+    module_namespace.reset(scope);
+
+    let key = v8::String::new(scope, name).unwrap();
+    let value = match namespace.get(scope, context, key.into()) {
+      Some(value) if !value.is_undefined() => value,
+      _ => return Ok(None),
+    };
+    Ok(
+      v8::json::stringify(context, value)
+        .map(|s| s.to_rust_string_lossy(scope)),
+    )
+  }
+
+  /// Resolves `specifier` with the loader, relative to the module with id
+  /// `referrer_id`. Used by `import.meta.resolve()`.
+  /// Returns None if that module has been removed since.
+  pub(crate) fn resolve_from_module(
+    &self,
+    specifier: &str,
+    referrer_id: ModuleId,
+  ) -> Option<Result<ModuleSpecifier, ErrBox>> {
+    let referrer = self.modules.get_name(referrer_id)?;
+    Some(self.loader.resolve(specifier, referrer, false))
+  }
+
+  /// Associates host-defined options, e.g. a content security policy or
+  /// integrity metadata, with the script or module called `resource_name`.
+  /// Dynamic imports from it pass the options to
+  /// `ModuleLoader::check_dynamic_import()`.
+  pub fn set_host_defined_options(
+    &mut self,
+    resource_name: &str,
+    options: Vec<String>,
+  ) {
+    self
+      .host_defined_options
+      .insert(resource_name.to_string(), options);
+  }
+
+  /// Returns the specifier and referrer of the last import that could not be
+  /// resolved while instantiating a module, so that the loader can produce a
+  /// more helpful diagnostic than the thrown exception. Cleared by the next
+  /// successful instantiation.
+  pub fn last_resolve_error(&self) -> Option<(&str, &str)> {
+    self
+      .last_resolve_error
+      .as_ref()
+      .map(|(specifier, referrer)| (specifier.as_str(), referrer.as_str()))
+  }
+
+  /// Returns the chain of imports that led to the last import that could not
+  /// be resolved: the name of the instantiated module, the names of the
+  /// modules it imports on the way, and finally the unresolved specifier, so
+  /// that loaders can print "a.js imports b.js imports ./c.js (failed)".
+  /// Empty unless `EsIsolate::last_resolve_error()` is set.
+  pub fn last_resolve_error_path(&self) -> &[String] {
+    &self.last_resolve_error_path
   }
 
   // Called by V8 during `Isolate::mod_instantiate`.
@@ -267,7 +692,10 @@ impl EsIsolate {
     specifier: &str,
     referrer_id: ModuleId,
   ) -> ModuleId {
-    let referrer = self.modules.get_name(referrer_id).unwrap();
+    let referrer = match self.modules.get_name(referrer_id) {
+      Some(referrer) => referrer,
+      None => return 0,
+    };
     let specifier = self
       .loader
       .resolve(specifier, referrer, false)
@@ -275,7 +703,6 @@ impl EsIsolate {
     self.modules.get_id(specifier.as_str()).unwrap_or(0)
   }
 
-  // Called by V8 during `Isolate::mod_instantiate`.
   pub fn dyn_import_cb(
     &mut self,
     specifier: &str,
@@ -294,6 +721,24 @@ impl EsIsolate {
     self.pending_dyn_imports.push(load.into_future());
   }
 
+  /// Same as `EsIsolate::dyn_import_cb()`, for a specifier that has been
+  /// resolved already.
+  pub(crate) fn dyn_import_resolved_cb(
+    &mut self,
+    module_specifier: ModuleSpecifier,
+    id: DynImportId,
+  ) {
+    debug!("dyn_import resolved specifier {}", module_specifier);
+
+    let load = RecursiveModuleLoad::dynamic_import_resolved(
+      id,
+      module_specifier,
+      self.loader.clone(),
+    );
+    self.waker.wake();
+    self.pending_dyn_imports.push(load.into_future());
+  }
+
   fn dyn_import_error(
     &mut self,
     id: DynImportId,
@@ -420,6 +865,8 @@ impl EsIsolate {
       code,
       module_url_specified,
       module_url_found,
+      line_offset,
+      column_offset,
     } = info;
 
     let is_main =
@@ -452,7 +899,13 @@ impl EsIsolate {
         id
       }
       // Module not registered yet, do it now.
-      None => self.mod_new(is_main, &module_url_found, &code)?,
+      None => self.mod_new_with_offset(
+        is_main,
+        &module_url_found,
+        &code,
+        line_offset,
+        column_offset,
+      )?,
     };
 
     // Now we must iterate over all imports of the module and load them.
@@ -503,6 +956,18 @@ impl EsIsolate {
   }
 }
 
+/// Returns a human-readable name for a module status, for use in diagnostics.
+pub fn module_status_str(status: v8::ModuleStatus) -> &'static str {
+  match status {
+    v8::ModuleStatus::Uninstantiated => "uninstantiated",
+    v8::ModuleStatus::Instantiating => "instantiating",
+    v8::ModuleStatus::Instantiated => "instantiated",
+    v8::ModuleStatus::Evaluating => "evaluating",
+    v8::ModuleStatus::Evaluated => "evaluated",
+    v8::ModuleStatus::Errored => "errored",
+  }
+}
+
 impl Future for EsIsolate {
   type Output = Result<(), ErrBox>;
 
@@ -538,6 +1003,7 @@ pub mod tests {
   use crate::isolate::ZeroCopyBuf;
   use crate::modules::ModuleSourceFuture;
   use crate::ops::*;
+  use std::cell::RefCell;
   use std::io;
   use std::sync::atomic::{AtomicUsize, Ordering};
   use std::sync::Arc;
@@ -641,6 +1107,206 @@ pub mod tests {
     assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
   }
 
+  struct NoImportsLoader;
+
+  impl ModuleLoader for NoImportsLoader {
+    fn resolve(
+      &self,
+      _specifier: &str,
+      _referrer: &str,
+      _is_main: bool,
+    ) -> Result<ModuleSpecifier, ErrBox> {
+      unreachable!()
+    }
+
+    fn load(
+      &self,
+      _module_specifier: &ModuleSpecifier,
+      _maybe_referrer: Option<ModuleSpecifier>,
+      _is_dyn_import: bool,
+    ) -> Pin<Box<ModuleSourceFuture>> {
+      unreachable!()
+    }
+  }
+
+  #[test]
+  fn module_namespace() {
+    let loader = Rc::new(NoImportsLoader);
+    let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+    let mod_id = isolate
+      .mod_new(
+        true,
+        "file:///config.js",
+        r#"
+        export const config = { port: 8080, hosts: ["a", "b"] };
+        export default 42;
+      "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_id));
+    js_check(isolate.mod_evaluate(mod_id));
+
+    assert_eq!(
+      isolate.get_module_export_json(mod_id, "config").unwrap(),
+      Some(r#"{"port":8080,"hosts":["a","b"]}"#.to_string())
+    );
+    assert_eq!(
+      isolate.get_module_export_json(mod_id, "default").unwrap(),
+      Some("42".to_string())
+    );
+    assert_eq!(
+      isolate.get_module_export_json(mod_id, "missing").unwrap(),
+      None
+    );
+
+    // Unknown and not yet evaluated modules are errors rather than panics.
+    assert!(isolate.get_module_namespace(mod_id + 1).is_err());
+    let mod_id = isolate
+      .mod_new(false, "file:///pending.js", "export default 1;")
+      .unwrap();
+    assert!(isolate.get_module_namespace(mod_id).is_err());
+  }
+
+  #[test]
+  fn mod_status() {
+    let loader = Rc::new(NoImportsLoader);
+    let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+
+    let mod_ok = isolate
+      .mod_new(true, "file:///ok.js", "export const a = 1;")
+      .unwrap();
+    assert_eq!(
+      module_status_str(isolate.mod_status(mod_ok).unwrap()),
+      "uninstantiated"
+    );
+    js_check(isolate.mod_instantiate(mod_ok));
+    assert_eq!(
+      isolate.mod_status(mod_ok),
+      Some(v8::ModuleStatus::Instantiated)
+    );
+    js_check(isolate.mod_evaluate(mod_ok));
+    assert_eq!(
+      isolate.mod_status(mod_ok),
+      Some(v8::ModuleStatus::Evaluated)
+    );
+
+    let mod_err = isolate
+      .mod_new(false, "file:///err.js", "throw new Error('boom');")
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_err));
+    assert!(isolate.mod_evaluate(mod_err).is_err());
+    assert_eq!(
+      module_status_str(isolate.mod_status(mod_err).unwrap()),
+      "errored"
+    );
+
+    assert_eq!(isolate.mod_status(mod_err + 1), None);
+  }
+
+  #[test]
+  fn on_module_evaluated() {
+    let loader = Rc::new(NoImportsLoader);
+    let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+    let evaluated = Rc::new(RefCell::new(vec![]));
+    let evaluated_ = evaluated.clone();
+    isolate.set_on_module_evaluated(move |id, ok| {
+      evaluated_.borrow_mut().push((id, ok));
+    });
+
+    let mod_ok = isolate
+      .mod_new(true, "file:///ok.js", "export const a = 1;")
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_ok));
+    js_check(isolate.mod_evaluate(mod_ok));
+    js_check(isolate.mod_evaluate(mod_ok));
+    assert_eq!(*evaluated.borrow(), vec![(mod_ok, true)]);
+
+    let mod_err = isolate
+      .mod_new(false, "file:///err.js", "throw new Error('boom');")
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_err));
+    assert!(isolate.mod_evaluate(mod_err).is_err());
+    assert!(isolate.mod_evaluate(mod_err).is_err());
+    assert_eq!(*evaluated.borrow(), vec![(mod_ok, true), (mod_err, false)]);
+  }
+
+  #[test]
+  fn mod_remove_and_clear() {
+    let loader = Rc::new(NoImportsLoader);
+    let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+
+    // Repeatedly registering and removing the same module must not
+    // accumulate entries, and ids are never reused.
+    let mut last_id = 0;
+    for i in 0..100 {
+      let source = format!("export const a = {};", i);
+      let mod_id = isolate.mod_new(true, "file:///a.js", &source).unwrap();
+      assert!(mod_id > last_id);
+      last_id = mod_id;
+      let identity_hash =
+        isolate.modules.get_info(mod_id).unwrap().identity_hash;
+      assert_eq!(
+        isolate.modules.get_id_by_identity_hash(identity_hash),
+        Some(mod_id)
+      );
+      js_check(isolate.mod_instantiate(mod_id));
+      js_check(isolate.mod_evaluate(mod_id));
+      assert_eq!(isolate.modules.get_id("file:///a.js"), Some(mod_id));
+      if i % 2 == 0 {
+        isolate.mod_remove(mod_id);
+      } else {
+        let info = isolate.take_module(mod_id).unwrap();
+        assert!(info.handle.is_empty());
+      }
+      assert!(isolate.modules.get_info(mod_id).is_none());
+      assert_eq!(isolate.modules.get_id("file:///a.js"), None);
+      assert_eq!(isolate.modules.get_id_by_identity_hash(identity_hash), None);
+    }
+    assert!(isolate.modules.info.is_empty());
+
+    let mod_b = isolate
+      .mod_new(false, "file:///b.js", "export const b = 1;")
+      .unwrap();
+    isolate.modules.alias("file:///c.js", "file:///b.js");
+    assert_eq!(isolate.modules.get_id("file:///c.js"), Some(mod_b));
+    let infos = isolate.take_all_modules();
+    assert_eq!(infos.len(), 1);
+    assert!(infos.iter().all(|info| info.handle.is_empty()));
+    assert!(isolate.modules.info.is_empty());
+    assert_eq!(isolate.modules.get_id("file:///b.js"), None);
+    assert_eq!(isolate.modules.get_id("file:///c.js"), None);
+  }
+
+  #[test]
+  fn mod_evaluate_top_level_await() {
+    let loader = Rc::new(NoImportsLoader);
+    let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+    let mod_id = isolate
+      .mod_new(
+        true,
+        "file:///tla.js",
+        r#"
+        await new Promise((resolve) => {
+          globalThis.resolveTla = resolve;
+        });
+        globalThis.tlaDone = true;
+      "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_id));
+    js_check(isolate.mod_evaluate(mod_id));
+
+    assert!(isolate.poll_mod_evaluate(mod_id).is_pending());
+    js_check(isolate.execute("check1.js", "if (globalThis.tlaDone) throw 1"));
+
+    js_check(isolate.execute("resolve.js", "resolveTla()"));
+    match isolate.poll_mod_evaluate(mod_id) {
+      Poll::Ready(Ok(())) => {}
+      _ => unreachable!(),
+    };
+    js_check(isolate.execute("check2.js", "if (!globalThis.tlaDone) throw 2"));
+  }
+
   #[test]
   fn dyn_import_err() {
     #[derive(Clone, Default)]
@@ -738,6 +1404,8 @@ pub mod tests {
           module_url_specified: specifier.to_string(),
           module_url_found: specifier.to_string(),
           code: "# not valid JS".to_owned(),
+          line_offset: 0,
+          column_offset: 0,
         };
         async move { Ok(info) }.boxed()
       }
@@ -823,6 +1491,8 @@ pub mod tests {
           module_url_specified: specifier.to_string(),
           module_url_found: specifier.to_string(),
           code: "export function b() { return 'b' }".to_owned(),
+          line_offset: 0,
+          column_offset: 0,
         };
         async move { Ok(info) }.boxed()
       }
@@ -866,4 +1536,379 @@ pub mod tests {
       assert_eq!(load_count.load(Ordering::Relaxed), 2);
     })
   }
+
+  #[test]
+  fn last_resolve_error() {
+    struct MissingLoader;
+
+    impl ModuleLoader for MissingLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        Ok(ModuleSpecifier::resolve_import(specifier, referrer).unwrap())
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!()
+      }
+    }
+
+    let loader = Rc::new(MissingLoader);
+    let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+    assert_eq!(isolate.last_resolve_error(), None);
+
+    let mod_a = isolate
+      .mod_new(true, "file:///a.js", "import './missing.js';")
+      .unwrap();
+    assert!(isolate.mod_instantiate(mod_a).is_err());
+    assert_eq!(
+      isolate.last_resolve_error(),
+      Some(("./missing.js", "file:///a.js"))
+    );
+    assert_eq!(
+      isolate.last_resolve_error_path(),
+      &["file:///a.js", "./missing.js"]
+    );
+
+    let mod_b = isolate.mod_new(false, "file:///b.js", "").unwrap();
+    js_check(isolate.mod_instantiate(mod_b));
+    assert_eq!(isolate.last_resolve_error(), None);
+    assert!(isolate.last_resolve_error_path().is_empty());
+
+    // The path leads through the modules in between.
+    isolate
+      .mod_new(false, "file:///d.js", "import './missing.js';")
+      .unwrap();
+    let mod_c = isolate
+      .mod_new(false, "file:///c.js", "import './d.js';")
+      .unwrap();
+    assert!(isolate.mod_instantiate(mod_c).is_err());
+    assert_eq!(
+      isolate.last_resolve_error_path(),
+      &["file:///c.js", "file:///d.js", "./missing.js"]
+    );
+  }
+
+  #[test]
+  fn resolve_cache() {
+    #[derive(Default)]
+    struct CountingLoader {
+      pub resolved: RefCell<Vec<(String, String)>>,
+    }
+
+    impl ModuleLoader for CountingLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        self
+          .resolved
+          .borrow_mut()
+          .push((specifier.to_string(), referrer.to_string()));
+        Ok(ModuleSpecifier::resolve_import(specifier, referrer).unwrap())
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!()
+      }
+    }
+
+    // a imports b, which imports the not yet registered c.
+    let loader = Rc::new(CountingLoader::default());
+    let mut isolate = EsIsolate::new(loader.clone(), StartupData::None, false);
+    let mod_a = isolate
+      .mod_new(true, "file:///a.js", "import './b.js';")
+      .unwrap();
+    isolate
+      .mod_new(false, "file:///b.js", "import './c.js';")
+      .unwrap();
+    loader.resolved.borrow_mut().clear();
+
+    assert!(isolate.mod_instantiate(mod_a).is_err());
+    assert!(isolate.resolve_cache.is_empty());
+    // The cache doesn't outlive a call, so the retry asks the loader about
+    // every pair again.
+    isolate.mod_new(false, "file:///c.js", "").unwrap();
+    js_check(isolate.mod_instantiate(mod_a));
+    assert!(isolate.resolve_cache.is_empty());
+    let count = |specifier: &str, referrer: &str| {
+      loader
+        .resolved
+        .borrow()
+        .iter()
+        .filter(|(s, r)| s == specifier && r == referrer)
+        .count()
+    };
+    assert_eq!(count("./b.js", "file:///a.js"), 2);
+    assert_eq!(count("./c.js", "file:///b.js"), 2);
+  }
+
+  #[test]
+  fn mod_evaluate_unexpected_status() {
+    struct NoopLoader;
+
+    impl ModuleLoader for NoopLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        Ok(ModuleSpecifier::resolve_import(specifier, referrer).unwrap())
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!()
+      }
+    }
+
+    let mut isolate =
+      EsIsolate::new(Rc::new(NoopLoader), StartupData::None, false);
+    let mod_a = isolate.mod_new(true, "file:///a.js", "").unwrap();
+
+    // Not instantiated yet.
+    let err = isolate.mod_evaluate(mod_a).unwrap_err();
+    assert!(err.to_string().contains("Unexpected status"));
+    assert!(err.to_string().contains("file:///a.js"));
+
+    // Unknown module id.
+    assert!(isolate.mod_evaluate(mod_a + 1).is_err());
+
+    js_check(isolate.mod_instantiate(mod_a));
+    js_check(isolate.mod_evaluate(mod_a));
+  }
+
+  #[test]
+  fn mod_new_syntax_error() {
+    struct NoopLoader;
+
+    impl ModuleLoader for NoopLoader {
+      fn resolve(
+        &self,
+        _specifier: &str,
+        _referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        unreachable!()
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!()
+      }
+    }
+
+    let mut isolate =
+      EsIsolate::new(Rc::new(NoopLoader), StartupData::None, false);
+    let err = isolate
+      .mod_new(true, "file:///bad.js", "export const = ;")
+      .unwrap_err();
+    let js_error = err.downcast::<crate::JSError>().unwrap();
+    assert!(js_error.message.starts_with("Uncaught SyntaxError"));
+    assert_eq!(
+      js_error.script_resource_name.as_deref(),
+      Some("file:///bad.js")
+    );
+    assert!(isolate.modules.get_id("file:///bad.js").is_none());
+  }
+
+  #[test]
+  fn dyn_import_registered_module() {
+    struct RegistryLoader;
+
+    impl ModuleLoader for RegistryLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        Ok(ModuleSpecifier::resolve_import(specifier, referrer).unwrap())
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!("registered modules should not be loaded")
+      }
+    }
+
+    let mut isolate =
+      EsIsolate::new(Rc::new(RegistryLoader), StartupData::None, false);
+    let mod_b = isolate
+      .mod_new(false, "file:///b.js", "export const b = 'b';")
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_b));
+    js_check(isolate.mod_evaluate(mod_b));
+
+    let mod_a = isolate
+      .mod_new(
+        true,
+        "file:///a.js",
+        "import('./b.js').then((ns) => { globalThis.result = ns.b; });",
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_a));
+    js_check(isolate.mod_evaluate(mod_a));
+    js_check(
+      isolate.execute("check.js", "if (result !== 'b') throw Error(result)"),
+    );
+  }
+
+  #[test]
+  fn dyn_import_host_defined_options() {
+    struct PolicyLoader;
+
+    impl ModuleLoader for PolicyLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        Ok(ModuleSpecifier::resolve_import(specifier, referrer).unwrap())
+      }
+
+      fn check_dynamic_import(
+        &self,
+        specifier: &str,
+        _referrer: &str,
+        host_defined_options: &[String],
+      ) -> Result<(), ErrBox> {
+        if host_defined_options
+          .iter()
+          .any(|o| o == "no-dynamic-import")
+        {
+          let msg = format!("Dynamic import of {} is not allowed", specifier);
+          return Err(ErrBox::from(io::Error::new(io::ErrorKind::Other, msg)));
+        }
+        Ok(())
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!("the import should have been rejected")
+      }
+    }
+
+    let mut isolate =
+      EsIsolate::new(Rc::new(PolicyLoader), StartupData::None, false);
+    isolate.set_host_defined_options(
+      "file:///a.js",
+      vec!["no-dynamic-import".to_string()],
+    );
+    let mod_a = isolate
+      .mod_new(
+        true,
+        "file:///a.js",
+        "import('./b.js').catch((e) => { globalThis.error = e.message; });",
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_a));
+    js_check(isolate.mod_evaluate(mod_a));
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+        if (error !== "Dynamic import of ./b.js is not allowed") {
+          throw Error(error);
+        }
+      "#,
+    ));
+  }
+
+  #[test]
+  fn import_meta_resolve() {
+    struct ResolveLoader;
+
+    impl ModuleLoader for ResolveLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        if specifier == "bad" {
+          return Err(ErrBox::from(io::Error::from(io::ErrorKind::NotFound)));
+        }
+        Ok(ModuleSpecifier::resolve_import(specifier, referrer).unwrap())
+      }
+
+      fn load(
+        &self,
+        _module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        unreachable!()
+      }
+    }
+
+    let mut isolate =
+      EsIsolate::new(Rc::new(ResolveLoader), StartupData::None, false);
+    let mod_a = isolate
+      .mod_new(
+        true,
+        "file:///dir/a.js",
+        r#"
+          if (import.meta.resolve("./b.js") !== "file:///dir/b.js") {
+            throw Error("bad resolution");
+          }
+          try {
+            import.meta.resolve("bad");
+            throw Error("expected TypeError");
+          } catch (e) {
+            if (!(e instanceof TypeError)) throw e;
+          }
+          globalThis.resolve = import.meta.resolve;
+        "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_a));
+    js_check(isolate.mod_evaluate(mod_a));
+
+    // A retained resolve function throws once its module is gone.
+    isolate.mod_remove(mod_a);
+    js_check(isolate.execute(
+      "removed.js",
+      r#"
+        try {
+          resolve("./b.js");
+          throw Error("expected TypeError");
+        } catch (e) {
+          if (!(e instanceof TypeError)) throw e;
+        }
+      "#,
+    ));
+  }
 }
@@ -13,6 +13,8 @@ use crate::js_errors::JSError;
 use crate::ops::*;
 use crate::shared_queue::SharedQueue;
 use crate::shared_queue::RECOMMENDED_SIZE;
+use futures::future::abortable;
+use futures::future::AbortHandle;
 use futures::future::FutureExt;
 use futures::stream::select;
 use futures::stream::FuturesUnordered;
@@ -20,8 +22,11 @@ use futures::stream::StreamExt;
 use futures::task::AtomicWaker;
 use futures::Future;
 use libc::c_void;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::convert::From;
+use std::convert::TryFrom;
+use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
 use std::mem::forget;
@@ -29,17 +34,28 @@ use std::ops::{Deref, DerefMut};
 use std::option::Option;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex, Once};
 use std::task::Context;
 use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
-type PendingOpFuture = Pin<Box<dyn Future<Output = (OpId, Buf)>>>;
+/// Resolves to the op id, the key in `Isolate::pending_op_aborts` and the
+/// response, which is `None` if the op was aborted.
+type PendingOpFuture = Pin<Box<dyn Future<Output = (OpId, u64, Option<Buf>)>>>;
 
 /// A ZeroCopyBuf encapsulates a slice that's been borrowed from a JavaScript
 /// ArrayBuffer object. JavaScript objects can normally be garbage collected,
 /// but the existence of a ZeroCopyBuf inhibits this until it is dropped. It
-/// behaves much like an Arc<[u8]>, although a ZeroCopyBuf currently can't be
-/// cloned.
+/// behaves much like an Arc<[u8]>: cloning a ZeroCopyBuf is cheap and the
+/// ArrayBuffer stays alive until the last clone is dropped.
+///
+/// Note that, unlike an Arc<[u8]>, a ZeroCopyBuf hands out mutable access to
+/// the underlying memory. All clones (as well as the JavaScript side) alias
+/// the same bytes, so writes through one clone are visible through the others.
+#[derive(Clone)]
 pub struct ZeroCopyBuf {
   backing_store: v8::SharedRef<v8::BackingStore>,
   byte_offset: usize,
@@ -49,6 +65,7 @@ pub struct ZeroCopyBuf {
 unsafe impl Send for ZeroCopyBuf {}
 
 impl ZeroCopyBuf {
+  /// Panics if the ArrayBuffer behind `view` has been detached.
   pub fn new(view: v8::Local<v8::ArrayBufferView>) -> Self {
     let backing_store = view.buffer().unwrap().get_backing_store();
     let byte_offset = view.byte_offset();
@@ -147,8 +164,159 @@ pub enum StartupData<'a> {
   None,
 }
 
-type JSErrorCreateFn = dyn Fn(JSError) -> ErrBox;
+pub(crate) type JSErrorCreateFn = dyn Fn(JSError) -> ErrBox;
 type IsolateErrorHandleFn = dyn FnMut(ErrBox) -> Result<(), ErrBox>;
+type NearHeapLimitCallbackFn = dyn FnMut(usize, usize) -> usize;
+type DispatchFn = dyn FnMut(OpId, &[u8], Option<ZeroCopyBuf>) -> Op;
+
+/// Caps the amount of memory the V8 heap of an isolate may use. A value of 0
+/// leaves the corresponding V8 default in place.
+#[derive(Default, Clone, Copy)]
+pub struct HeapLimits {
+  /// The maximum size of the old generation in bytes. When the heap
+  /// approaches this size, V8 performs a series of garbage collections and
+  /// then invokes the callback registered with
+  /// `Isolate::add_near_heap_limit_callback()`. If no callback raises the
+  /// limit, V8 will crash with a fatal process out of memory.
+  pub max_old_generation_size: usize,
+  /// The maximum size of the young generation in bytes.
+  pub max_young_generation_size: usize,
+}
+
+/// Configures and creates an `Isolate`. Everything that is not set explicitly
+/// falls back to a default: no startup script or snapshot, no snapshotting,
+/// no heap limits and a shared queue of `RECOMMENDED_SIZE` bytes.
+pub struct IsolateBuilder<'a> {
+  startup_data: Option<StartupData<'a>>,
+  will_snapshot: bool,
+  heap_limits: Option<HeapLimits>,
+  shared_queue_size: usize,
+  microtasks_policy: Option<v8::MicrotasksPolicy>,
+  stack_trace_limit: Option<usize>,
+  array_buffer_allocator: Option<v8::UniqueRef<v8::Allocator>>,
+  expose_gc: bool,
+  terminate_on_heap_limit: bool,
+  external_references: &'static v8::ExternalReferences,
+}
+
+impl Default for IsolateBuilder<'_> {
+  fn default() -> Self {
+    Self {
+      startup_data: None,
+      will_snapshot: false,
+      heap_limits: None,
+      shared_queue_size: RECOMMENDED_SIZE,
+      microtasks_policy: None,
+      stack_trace_limit: None,
+      array_buffer_allocator: None,
+      expose_gc: false,
+      terminate_on_heap_limit: false,
+      external_references: &bindings::EXTERNAL_REFERENCES,
+    }
+  }
+}
+
+impl<'a> IsolateBuilder<'a> {
+  /// Creates a builder with the defaults described above.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Defines the snapshot or script used at startup to initialize the
+  /// isolate.
+  pub fn startup_data(mut self, startup_data: StartupData<'a>) -> Self {
+    self.startup_data = Some(startup_data);
+    self
+  }
+
+  /// Creates the isolate such that `Isolate::snapshot()` can be called.
+  pub fn will_snapshot(mut self, will_snapshot: bool) -> Self {
+    self.will_snapshot = will_snapshot;
+    self
+  }
+
+  /// Caps the size of the V8 heap. Heap limits are not applied to isolates
+  /// that will be snapshotted.
+  pub fn heap_limits(mut self, heap_limits: HeapLimits) -> Self {
+    self.heap_limits = Some(heap_limits);
+    self
+  }
+
+  /// Sets the size in bytes of the records area of the shared queue that is
+  /// used to pass async op responses to JavaScript.
+  pub fn shared_queue_size(mut self, shared_queue_size: usize) -> Self {
+    self.shared_queue_size = shared_queue_size;
+    self
+  }
+
+  /// Sets when V8 runs the microtask queue. See
+  /// `Isolate::set_microtasks_policy()`.
+  pub fn microtasks_policy(
+    mut self,
+    microtasks_policy: v8::MicrotasksPolicy,
+  ) -> Self {
+    self.microtasks_policy = Some(microtasks_policy);
+    self
+  }
+
+  /// Sets how many stack frames are captured for errors. See
+  /// `Isolate::set_stack_trace_limit()`. `Error.stackTraceLimit` is left
+  /// alone if the startup snapshot replaced `Error` with a non-object.
+  pub fn stack_trace_limit(mut self, stack_trace_limit: usize) -> Self {
+    self.stack_trace_limit = Some(stack_trace_limit);
+    self
+  }
+
+  /// Sets the allocator used for the backing stores of ArrayBuffers, e.g. to
+  /// account for or cap the memory allocated by untrusted code. Defaults to
+  /// `v8::new_default_allocator()`. Like heap limits, this is not applied to
+  /// isolates that will be snapshotted.
+  pub fn array_buffer_allocator(
+    mut self,
+    allocator: v8::UniqueRef<v8::Allocator>,
+  ) -> Self {
+    self.array_buffer_allocator = Some(allocator);
+    self
+  }
+
+  /// Adds `Deno.core.gc()`, which requests a full garbage collection, e.g. so
+  /// that leak tests can reclaim memory before sampling heap statistics. See
+  /// `Isolate::low_memory_notification()`.
+  pub fn expose_gc(mut self, expose_gc: bool) -> Self {
+    self.expose_gc = expose_gc;
+    self
+  }
+
+  /// Terminates the isolate when its heap approaches the limit set with
+  /// `IsolateBuilder::heap_limits()`, instead of letting V8 crash the whole
+  /// process with a fatal out of memory error. The failing call then returns
+  /// a `HeapLimitExceeded` error, after which the isolate should be dropped.
+  /// A callback registered with `Isolate::add_near_heap_limit_callback()`
+  /// takes precedence.
+  pub fn terminate_on_heap_limit(mut self, terminate: bool) -> Self {
+    self.terminate_on_heap_limit = terminate;
+    self
+  }
+
+  /// Registers the embedder's own native functions, in addition to the
+  /// built-in bindings, so that functions they back survive snapshotting.
+  /// V8 serializes such functions as an index into this table, so the isolate
+  /// that loads the snapshot must be given the same references in the same
+  /// order. Build the table once with `external_references()`, e.g. in a
+  /// `lazy_static!`, since it must outlive every isolate that uses it.
+  pub fn external_references(
+    mut self,
+    external_references: &'static v8::ExternalReferences,
+  ) -> Self {
+    self.external_references = external_references;
+    self
+  }
+
+  /// Creates the isolate.
+  pub fn build(self) -> Box<Isolate> {
+    Isolate::from_builder(self)
+  }
+}
 
 /// A single execution context of JavaScript. Corresponds roughly to the "Web
 /// Worker" concept in the DOM. An Isolate is a Future that can be used with
@@ -166,6 +334,8 @@ pub struct Isolate {
   snapshot: Option<SnapshotConfig>,
   pub global_context: v8::Global<v8::Context>,
   pub(crate) shared_ab: v8::Global<v8::SharedArrayBuffer>,
+  pub(crate) fast_channel: Option<v8::SharedRef<v8::BackingStore>>,
+  pub(crate) fast_channel_ab: v8::Global<v8::SharedArrayBuffer>,
   pub(crate) js_recv_cb: v8::Global<v8::Function>,
   pub(crate) js_macrotask_cb: v8::Global<v8::Function>,
   pub(crate) pending_promise_exceptions: HashMap<i32, v8::Global<v8::Value>>,
@@ -173,13 +343,128 @@ pub struct Isolate {
   pub(crate) js_error_create_fn: Box<JSErrorCreateFn>,
   needs_init: bool,
   pub(crate) shared: SharedQueue,
+  /// The size of the shared queue of each new realm.
+  shared_queue_size: usize,
   pending_ops: FuturesUnordered<PendingOpFuture>,
   pending_unref_ops: FuturesUnordered<PendingOpFuture>,
+  /// Handles to abort the pending async ops, with the realm each op was
+  /// dispatched from, see `Isolate::abort_op()`.
+  pending_op_aborts: HashMap<u64, (OpId, RealmId, AbortHandle)>,
+  next_pending_op: u64,
   have_unpolled_ops: bool,
   startup_script: Option<OwnedScript>,
   pub op_registry: Rc<OpRegistry>,
+  dispatcher: Option<Box<DispatchFn>>,
   waker: AtomicWaker,
   error_handler: Option<Box<IsolateErrorHandleFn>>,
+  near_heap_limit_callback: Option<Box<NearHeapLimitCallbackFn>>,
+  has_near_heap_limit_callback: bool,
+  pub(crate) heap_limit_reached: bool,
+  pub(crate) string_keys: HashMap<&'static str, v8::Global<v8::String>>,
+  pub(crate) start_time: Instant,
+  timer_op_id: Option<OpId>,
+  console_op_id: Option<OpId>,
+  pub(crate) next_timer_id: u32,
+  pub(crate) pending_timers: HashMap<u32, PendingTimer>,
+  /// Timers whose op has resolved but whose callback hasn't been called yet.
+  elapsed_timers: Vec<u32>,
+  op_stats: HashMap<OpId, OpStat>,
+  pub(crate) realms: Vec<Realm>,
+  watchdog: Option<Watchdog>,
+}
+
+/// Identifies a realm created with `Isolate::create_realm()`. `MAIN_REALM`
+/// is the context the isolate was created with.
+pub type RealmId = usize;
+
+pub const MAIN_REALM: RealmId = 0;
+
+/// An additional context, with its own globals, in the heap of an isolate.
+/// Like the main context, it has its own `Deno.core.recv()` callback, shared
+/// queue and fast channel.
+pub(crate) struct Realm {
+  pub context: v8::Global<v8::Context>,
+  pub js_recv_cb: v8::Global<v8::Function>,
+  pub shared: SharedQueue,
+  pub shared_ab: v8::Global<v8::SharedArrayBuffer>,
+  pub fast_channel: Option<v8::SharedRef<v8::BackingStore>>,
+  pub fast_channel_ab: v8::Global<v8::SharedArrayBuffer>,
+}
+
+/// A thread that terminates execution once a deadline has passed, see
+/// `Isolate::execute_with_timeout()`. It is started on first use and then
+/// reused, and exits when the isolate is dropped.
+struct Watchdog {
+  arm_tx: mpsc::Sender<Duration>,
+  disarm_tx: mpsc::Sender<()>,
+  timed_out_rx: mpsc::Receiver<bool>,
+}
+
+impl Watchdog {
+  fn new(v8_isolate_handle: v8::IsolateHandle) -> Self {
+    let (arm_tx, arm_rx) = mpsc::channel::<Duration>();
+    let (disarm_tx, disarm_rx) = mpsc::channel::<()>();
+    let (timed_out_tx, timed_out_rx) = mpsc::channel::<bool>();
+    thread::spawn(move || {
+      while let Ok(timeout) = arm_rx.recv() {
+        let timed_out = match disarm_rx.recv_timeout(timeout) {
+          Ok(()) => false,
+          Err(mpsc::RecvTimeoutError::Timeout) => {
+            let terminated = v8_isolate_handle.terminate_execution();
+            if disarm_rx.recv().is_err() {
+              break;
+            }
+            terminated
+          }
+          Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if timed_out_tx.send(timed_out).is_err() {
+          break;
+        }
+      }
+    });
+    Self {
+      arm_tx,
+      disarm_tx,
+      timed_out_rx,
+    }
+  }
+
+  /// Starts the countdown.
+  fn arm(&self, timeout: Duration) {
+    self.arm_tx.send(timeout).unwrap();
+  }
+
+  /// Stops the countdown. Returns true if execution was terminated because
+  /// the deadline had passed.
+  fn disarm(&self) -> bool {
+    self.disarm_tx.send(()).unwrap();
+    self.timed_out_rx.recv().unwrap()
+  }
+}
+
+/// Accounting for a single op, see `Isolate::op_stats()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpStat {
+  pub op_id: OpId,
+  /// How many times the op was dispatched.
+  pub calls: u64,
+  /// The size of the control and zero copy buffers passed to the op.
+  pub bytes_received: u64,
+  /// The size of the responses of the op, sync and async.
+  pub bytes_sent: u64,
+}
+
+/// The response of a sync op, returned to JavaScript by `Deno.core.send()`.
+pub enum SyncResponse {
+  Buf(Buf),
+  I32(i32),
+}
+
+/// A callback passed to `setTimeout()` that hasn't fired or been cleared yet.
+pub(crate) struct PendingTimer {
+  pub deadline: Instant,
+  pub callback: v8::Global<v8::Function>,
 }
 
 impl Drop for Isolate {
@@ -204,10 +489,34 @@ impl Drop for Isolate {
   }
 }
 
+/// Returns a table of external references for
+/// `IsolateBuilder::external_references()`: the built-in bindings followed by
+/// the embedder's own `extra` references.
+pub fn external_references(
+  extra: &[v8::ExternalReference],
+) -> v8::ExternalReferences {
+  bindings::external_references(extra)
+}
+
 static DENO_INIT: Once = Once::new();
 
+/// Initializes V8, passing `extra_flags` (e.g. `--expose-gc`) after the
+/// default flags, so they take precedence. Returns the flags that V8 did not
+/// understand.
+///
+/// This must be called exactly once, before any isolate is created. Creating
+/// an isolate first initializes V8 without extra flags, and this function
+/// panics if V8 has already been initialized.
+pub fn v8_init_with_flags(extra_flags: &[&str]) -> Vec<String> {
+  let mut unrecognized = None;
+  DENO_INIT.call_once(|| {
+    unrecognized = Some(unsafe { v8_init(extra_flags) });
+  });
+  unrecognized.expect("V8 has already been initialized")
+}
+
 #[allow(clippy::missing_safety_doc)]
-pub unsafe fn v8_init() {
+pub unsafe fn v8_init(extra_flags: &[&str]) -> Vec<String> {
   let platform = v8::new_default_platform();
   v8::V8::initialize_platform(platform);
   v8::V8::initialize();
@@ -215,27 +524,51 @@ pub unsafe fn v8_init() {
   // remove this to make it work asynchronously too. But that requires getting
   // PumpMessageLoop and RunMicrotasks setup correctly.
   // See https://github.com/denoland/deno/issues/2544
-  let argv = vec![
+  let mut argv = vec![
     "".to_string(),
     "--no-wasm-async-compilation".to_string(),
     "--harmony-top-level-await".to_string(),
   ];
-  v8::V8::set_flags_from_command_line(argv);
+  argv.extend(extra_flags.iter().map(|flag| flag.to_string()));
+  let mut unrecognized = v8::V8::set_flags_from_command_line(argv);
+  // The first element is the program name.
+  unrecognized.remove(0);
+  unrecognized
 }
 
 impl Isolate {
   /// startup_data defines the snapshot or script used at startup to initialize
   /// the isolate.
   pub fn new(startup_data: StartupData, will_snapshot: bool) -> Box<Self> {
+    IsolateBuilder::new()
+      .startup_data(startup_data)
+      .will_snapshot(will_snapshot)
+      .build()
+  }
+
+  fn from_builder(builder: IsolateBuilder) -> Box<Self> {
     DENO_INIT.call_once(|| {
-      unsafe { v8_init() };
+      unsafe { v8_init(&[]) };
     });
 
+    let IsolateBuilder {
+      startup_data,
+      will_snapshot,
+      heap_limits,
+      shared_queue_size,
+      microtasks_policy,
+      stack_trace_limit,
+      array_buffer_allocator,
+      expose_gc,
+      terminate_on_heap_limit,
+      external_references,
+    } = builder;
+
     let mut load_snapshot: Option<SnapshotConfig> = None;
     let mut startup_script: Option<OwnedScript> = None;
 
     // Separate into Option values for each startup type
-    match startup_data {
+    match startup_data.unwrap_or(StartupData::None) {
       StartupData::Script(d) => {
         startup_script = Some(d.into());
       }
@@ -252,8 +585,7 @@ impl Isolate {
     let (mut isolate, maybe_snapshot_creator) = if will_snapshot {
       // TODO(ry) Support loading snapshots before snapshotting.
       assert!(load_snapshot.is_none());
-      let mut creator =
-        v8::SnapshotCreator::new(Some(&bindings::EXTERNAL_REFERENCES));
+      let mut creator = v8::SnapshotCreator::new(Some(external_references));
       let isolate = unsafe { creator.get_owned_isolate() };
       let mut isolate = Isolate::setup_isolate(isolate);
 
@@ -267,11 +599,25 @@ impl Isolate {
       (isolate, Some(creator))
     } else {
       let mut params = v8::Isolate::create_params();
-      params.set_array_buffer_allocator(v8::new_default_allocator());
-      params.set_external_references(&bindings::EXTERNAL_REFERENCES);
+      params.set_array_buffer_allocator(
+        array_buffer_allocator.unwrap_or_else(v8::new_default_allocator),
+      );
+      params.set_external_references(external_references);
       if let Some(ref mut snapshot) = load_snapshot {
         params.set_snapshot_blob(snapshot);
       }
+      if let Some(heap_limits) = heap_limits {
+        if heap_limits.max_old_generation_size > 0 {
+          params.set_max_old_generation_size_in_bytes(
+            heap_limits.max_old_generation_size,
+          );
+        }
+        if heap_limits.max_young_generation_size > 0 {
+          params.set_max_young_generation_size_in_bytes(
+            heap_limits.max_young_generation_size,
+          );
+        }
+      }
 
       let isolate = v8::Isolate::new(params);
       let mut isolate = Isolate::setup_isolate(isolate);
@@ -292,7 +638,11 @@ impl Isolate {
       (isolate, None)
     };
 
-    let shared = SharedQueue::new(RECOMMENDED_SIZE);
+    if let Some(microtasks_policy) = microtasks_policy {
+      isolate.set_microtasks_policy(microtasks_policy);
+    }
+
+    let shared = SharedQueue::new(shared_queue_size);
     let needs_init = true;
 
     let core_isolate = Self {
@@ -300,22 +650,41 @@ impl Isolate {
       global_context,
       pending_promise_exceptions: HashMap::new(),
       shared_ab: v8::Global::<v8::SharedArrayBuffer>::new(),
+      fast_channel: None,
+      fast_channel_ab: v8::Global::<v8::SharedArrayBuffer>::new(),
       js_recv_cb: v8::Global::<v8::Function>::new(),
       js_macrotask_cb: v8::Global::<v8::Function>::new(),
+      realms: Vec::new(),
+      watchdog: None,
       snapshot_creator: maybe_snapshot_creator,
       snapshot: load_snapshot,
       has_snapshotted: false,
       shared_isolate_handle: Arc::new(Mutex::new(None)),
       js_error_create_fn: Box::new(JSError::create),
       shared,
+      shared_queue_size,
       needs_init,
       pending_ops: FuturesUnordered::new(),
       pending_unref_ops: FuturesUnordered::new(),
+      pending_op_aborts: HashMap::new(),
+      next_pending_op: 0,
       have_unpolled_ops: false,
       startup_script,
       op_registry: Rc::new(OpRegistry::new()),
+      dispatcher: None,
       waker: AtomicWaker::new(),
       error_handler: None,
+      near_heap_limit_callback: None,
+      has_near_heap_limit_callback: false,
+      heap_limit_reached: false,
+      string_keys: HashMap::new(),
+      start_time: Instant::now(),
+      timer_op_id: None,
+      console_op_id: None,
+      next_timer_id: 1,
+      pending_timers: HashMap::new(),
+      elapsed_timers: Vec::new(),
+      op_stats: HashMap::new(),
     };
 
     let mut boxed_isolate = Box::new(core_isolate);
@@ -329,6 +698,28 @@ impl Isolate {
       boxed_isolate.v8_isolate = Some(isolate);
     }
 
+    if let Some(stack_trace_limit) = stack_trace_limit {
+      // Only fails if the startup snapshot replaced `Error`, in which case
+      // the limit still applies to uncaught exceptions.
+      let _ = boxed_isolate.set_stack_trace_limit(stack_trace_limit);
+    }
+
+    if terminate_on_heap_limit {
+      // Without a callback of its own, near_heap_limit_callback()
+      // terminates the isolate.
+      boxed_isolate.register_near_heap_limit_callback();
+    }
+
+    if expose_gc {
+      let v8_isolate = boxed_isolate.v8_isolate.as_mut().unwrap();
+      let mut hs = v8::HandleScope::new(v8_isolate);
+      let scope = hs.enter();
+      let context = boxed_isolate.global_context.get(scope).unwrap();
+      let mut cs = v8::ContextScope::new(scope, context);
+      let scope = cs.enter();
+      bindings::expose_gc(scope, context);
+    }
+
     boxed_isolate
   }
 
@@ -350,6 +741,255 @@ impl Isolate {
     self.op_registry.register(name, op)
   }
 
+  /// Registers the op that backs the global `setTimeout()`, and installs
+  /// `setTimeout()` and `clearTimeout()` on the global object. Isolates that
+  /// don't call this have neither.
+  ///
+  /// The control buffer passed to the op holds the timer id followed by the
+  /// delay in milliseconds, both as little endian u32. The op should return
+  /// an `Op::Async` that resolves with the 4 bytes of the timer id once the
+  /// delay has elapsed; the callback is then called from `Isolate::poll()`,
+  /// unless it has been cleared with `clearTimeout()` in the meantime.
+  pub fn register_timer_op<F>(&mut self, op: F) -> OpId
+  where
+    F: Fn(&[u8], Option<ZeroCopyBuf>) -> Op + 'static,
+  {
+    let op_id = self.op_registry.register("timer", op);
+    self.timer_op_id = Some(op_id);
+
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+    bindings::expose_timers(scope, context);
+
+    op_id
+  }
+
+  pub(crate) fn timer_op_id(&self) -> Option<OpId> {
+    self.timer_op_id
+  }
+
+  /// Registers the op that backs `Deno.core.dispatchConsole(method, args)`,
+  /// which forwards console calls to the host so that it decides how they
+  /// are formatted and where they go. Until this is called,
+  /// `dispatchConsole()` throws.
+  ///
+  /// The control buffer passed to the op holds the length of the method name
+  /// as little endian u32, the method name, and then the `args` array encoded
+  /// as JSON.
+  pub fn register_console_op<F>(&mut self, op: F) -> OpId
+  where
+    F: Fn(&[u8], Option<ZeroCopyBuf>) -> Op + 'static,
+  {
+    let op_id = self.op_registry.register("console", op);
+    self.console_op_id = Some(op_id);
+    op_id
+  }
+
+  pub(crate) fn console_op_id(&self) -> Option<OpId> {
+    self.console_op_id
+  }
+
+  /// Installs a single dispatcher that handles every op sent from JavaScript.
+  /// This is an alternative to registering individual ops with
+  /// `Isolate::register_op()` for embedders that prefer to route all ops
+  /// through one closure capturing their own state.
+  ///
+  /// Ops registered with `Isolate::register_op()` take precedence; the
+  /// dispatcher receives every op id that isn't found in the op registry.
+  pub fn set_dispatcher<F>(&mut self, f: F)
+  where
+    F: FnMut(OpId, &[u8], Option<ZeroCopyBuf>) -> Op + 'static,
+  {
+    self.dispatcher = Some(Box::new(f));
+  }
+
+  /// Sets when V8 runs the microtask queue, i.e. promise reactions and
+  /// callbacks passed to `Deno.core.queueMicrotask()`.
+  ///
+  /// With the default `v8::MicrotasksPolicy::Auto`, microtasks run whenever
+  /// the JavaScript call depth drops to zero, e.g. at the end of `execute()`
+  /// or of an async op response being delivered. With
+  /// `v8::MicrotasksPolicy::Explicit`, microtasks only run when the embedder
+  /// calls `Isolate::run_microtasks()`, which allows deterministic control of
+  /// the event loop.
+  pub fn set_microtasks_policy(&mut self, policy: v8::MicrotasksPolicy) {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    v8_isolate.set_microtasks_policy(policy);
+  }
+
+  /// Sets how many stack frames are captured for uncaught exceptions and for
+  /// the `stack` property of errors (`Error.stackTraceLimit`). Defaults to 10.
+  /// Limits above `i32::MAX` are clamped.
+  ///
+  /// Returns an error if a script replaced `Error` with a non-object or made
+  /// `Error.stackTraceLimit` throw; the limit for uncaught exceptions is
+  /// updated regardless.
+  pub fn set_stack_trace_limit(&mut self, limit: usize) -> Result<(), ErrBox> {
+    let limit = limit.min(i32::MAX as usize) as i32;
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    v8_isolate.set_capture_stack_trace_for_uncaught_exceptions(true, limit);
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let mut try_catch = v8::TryCatch::new(scope);
+    let tc = try_catch.enter();
+
+    let global = context.global(scope);
+    let error_key = bindings::v8_key(scope, "Error");
+    let error = match global.get(scope, context, error_key.into()) {
+      Some(error) => error,
+      None => {
+        let exception = tc.exception().unwrap();
+        return exception_to_err_result(scope, exception, js_error_create_fn);
+      }
+    };
+    let error = match v8::Local::<v8::Object>::try_from(error) {
+      Ok(error) => error,
+      Err(_) => {
+        let msg = v8::String::new(scope, "Error is not an object").unwrap();
+        let exception = v8::Exception::type_error(scope, msg);
+        return exception_to_err_result(scope, exception, js_error_create_fn);
+      }
+    };
+    let limit_key = bindings::v8_key(scope, "stackTraceLimit");
+    let limit = v8::Integer::new(scope, limit);
+    match error.set(context, limit_key.into(), limit.into()) {
+      Some(_) => Ok(()),
+      None => {
+        let exception = tc.exception().unwrap();
+        exception_to_err_result(scope, exception, js_error_create_fn)
+      }
+    }
+  }
+
+  /// Runs all pending microtasks.
+  pub fn run_microtasks(&mut self) {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    v8_isolate.run_microtasks();
+  }
+
+  /// Detaches an ArrayBuffer, e.g. one obtained with `Isolate::get_global()`,
+  /// and returns its backing store, so that the memory can be handed to
+  /// another isolate, e.g. with `Isolate::respond_zero_copy()`. Afterwards
+  /// the ArrayBuffer has a byte length of 0 in this isolate, views over it
+  /// can't be created, and passing it to `Deno.core.send()` throws.
+  ///
+  /// Returns None if `buffer` is not an ArrayBuffer or can't be detached,
+  /// e.g. because it is backed by WebAssembly memory.
+  pub fn detach_array_buffer(
+    &mut self,
+    mut buffer: v8::Global<v8::Value>,
+  ) -> Option<v8::SharedRef<v8::BackingStore>> {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+
+    let value = buffer.get(scope).unwrap();
+    buffer.reset(scope);
+    let array_buffer = v8::Local::<v8::ArrayBuffer>::try_from(value).ok()?;
+    if !array_buffer.is_detachable() {
+      return None;
+    }
+    let backing_store = array_buffer.get_backing_store();
+    array_buffer.detach();
+    Some(backing_store)
+  }
+
+  /// Delivers the response of an async op to JavaScript in a Uint8Array that
+  /// is backed by `backing_store` itself, rather than copying the bytes into
+  /// the shared queue. Useful for large responses that the host already
+  /// holds in a stable buffer.
+  ///
+  /// The ArrayBuffer keeps a reference to the backing store, so the memory
+  /// stays alive for as long as either the host or JavaScript uses it, even
+  /// if the host drops its `SharedRef` first. The host must not write to the
+  /// memory while JavaScript may still read the response.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn respond_zero_copy(
+    &mut self,
+    op_id: OpId,
+    backing_store: v8::SharedRef<v8::BackingStore>,
+  ) -> Result<(), ErrBox> {
+    self.shared_init()?;
+    op_stat_mut(&mut self.op_stats, op_id).bytes_sent +=
+      backing_store.byte_length() as u64;
+
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    async_op_response(
+      scope,
+      Some((op_id, backing_store)),
+      &self.js_recv_cb,
+      js_error_create_fn,
+    )
+  }
+
+  /// Delivers an async op response to the callback that the given realm
+  /// passed to `Deno.core.recv()`.
+  ///
+  /// Returns an `UnknownRealm` error if `realm_id` was not returned by
+  /// `Isolate::create_realm()`.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn respond_in_realm(
+    &mut self,
+    realm_id: RealmId,
+    op_id: OpId,
+    buf: Buf,
+  ) -> Result<(), ErrBox> {
+    self.shared_init()?;
+    op_stat_mut(&mut self.op_stats, op_id).bytes_sent += buf.len() as u64;
+
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let (global_context, js_recv_cb) = realm_handles(
+      &self.global_context,
+      &self.js_recv_cb,
+      &self.realms,
+      realm_id,
+    )?;
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!global_context.is_empty());
+    let context = global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let backing_store =
+      v8::ArrayBuffer::new_backing_store_from_boxed_slice(buf).make_shared();
+    async_op_response(
+      scope,
+      Some((op_id, backing_store)),
+      js_recv_cb,
+      js_error_create_fn,
+    )
+  }
+
   /// Allows a callback to be set whenever a V8 exception is made. This allows
   /// the caller to wrap the JSError into an error. By default this callback
   /// is set to JSError::create.
@@ -360,8 +1000,40 @@ impl Isolate {
     self.js_error_create_fn = Box::new(f);
   }
 
+  /// Registers a callback that V8 invokes when the heap size approaches the
+  /// limit configured with `IsolateBuilder::heap_limits()`. The callback
+  /// receives the current and the initial heap limit and returns the new
+  /// heap limit. A typical callback terminates execution through a
+  /// `v8::IsolateHandle` and grants a little more room so the termination can
+  /// be processed, e.g. `current_limit * 2`.
+  ///
+  /// Registering a new callback replaces the previous one.
+  pub fn add_near_heap_limit_callback<C>(&mut self, cb: C)
+  where
+    C: FnMut(usize, usize) -> usize + 'static,
+  {
+    self.near_heap_limit_callback = Some(Box::new(cb));
+    self.register_near_heap_limit_callback();
+  }
+
+  fn register_near_heap_limit_callback(&mut self) {
+    let core_isolate_ptr: *mut Self = self;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    if self.has_near_heap_limit_callback {
+      v8_isolate.remove_near_heap_limit_callback(near_heap_limit_callback, 0);
+    }
+    v8_isolate.add_near_heap_limit_callback(
+      near_heap_limit_callback,
+      core_isolate_ptr as *mut c_void,
+    );
+    self.has_near_heap_limit_callback = true;
+  }
+
   /// Executes a bit of built-in JavaScript to provide Deno.sharedQueue.
-  pub(crate) fn shared_init(&mut self) {
+  ///
+  /// Errors thrown by the startup script are returned to the caller of the
+  /// first `execute()` or `poll()`, rather than aborting the process.
+  pub(crate) fn shared_init(&mut self) -> Result<(), ErrBox> {
     if self.needs_init {
       self.needs_init = false;
       js_check(
@@ -369,9 +1041,76 @@ impl Isolate {
       );
       // Maybe execute the startup script.
       if let Some(s) = self.startup_script.take() {
-        self.execute(&s.filename, &s.source).unwrap()
+        self.execute(&s.filename, &s.source)?;
       }
     }
+    Ok(())
+  }
+
+  /// Replaces the shared queue backing `Deno.core.shared` in the main realm
+  /// with a new one of `shared_queue_size` bytes, e.g. to grow it. The next access to
+  /// `Deno.core.shared` returns a SharedArrayBuffer over the new region, and
+  /// the built-in shared queue JS picks it up before it handles the next batch
+  /// of async op responses.
+  ///
+  /// Views that JavaScript created over the old SharedArrayBuffer keep its
+  /// backing store alive, so they never alias freed memory, but they no longer
+  /// observe op responses. Returns a `SharedQueueNotEmpty` error, and keeps
+  /// the current queue, while responses are waiting to be delivered to
+  /// JavaScript.
+  pub fn set_shared_queue_size(
+    &mut self,
+    shared_queue_size: usize,
+  ) -> Result<(), ErrBox> {
+    if self.shared.size() != 0 {
+      return Err(SharedQueueNotEmpty.into());
+    }
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    self.shared_ab.reset(v8_isolate);
+    self.shared = SharedQueue::new(shared_queue_size);
+    Ok(())
+  }
+
+  /// Sets up a fast channel of `size` bytes: a SharedArrayBuffer, separate
+  /// from the shared queue, that `Deno.core.dispatchFast()` uses to pass op
+  /// arguments and sync responses without copying them into and out of
+  /// Uint8Arrays. JavaScript writes the control buffer at the start of
+  /// `Deno.core.fastChannel()` and calls
+  /// `Deno.core.dispatchFast(opId, controlLength)`; the op sees that region
+  /// in place, and a sync response is written back to the start of the
+  /// channel, overwriting the control buffer.
+  ///
+  /// Calling this again replaces the channel; views over the old one keep it
+  /// alive but are no longer used. This sets up the channel of the main
+  /// realm, see `Isolate::setup_fast_channel_in_realm()`.
+  pub fn setup_fast_channel(&mut self, size: usize) {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    self.fast_channel_ab.reset(v8_isolate);
+    self.fast_channel = Some(new_fast_channel(size));
+  }
+
+  /// Same as `Isolate::setup_fast_channel()`, but sets up the channel of the
+  /// given realm.
+  ///
+  /// Returns an `UnknownRealm` error if `realm_id` was not returned by
+  /// `Isolate::create_realm()`.
+  pub fn setup_fast_channel_in_realm(
+    &mut self,
+    realm_id: RealmId,
+    size: usize,
+  ) -> Result<(), ErrBox> {
+    if realm_id == MAIN_REALM {
+      self.setup_fast_channel(size);
+      return Ok(());
+    }
+    let realm = match self.realms.get_mut(realm_id - 1) {
+      Some(realm) => realm,
+      None => return Err(UnknownRealm(realm_id).into()),
+    };
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    realm.fast_channel_ab.reset(v8_isolate);
+    realm.fast_channel = Some(new_fast_channel(size));
+    Ok(())
   }
 
   pub fn dispatch_op<'s>(
@@ -380,8 +1119,15 @@ impl Isolate {
     op_id: OpId,
     control_buf: &[u8],
     zero_copy_buf: Option<ZeroCopyBuf>,
-  ) -> Option<(OpId, Box<[u8]>)> {
-    let maybe_op = self.op_registry.call(op_id, control_buf, zero_copy_buf);
+  ) -> Option<(OpId, SyncResponse)> {
+    let bytes_received = control_buf.len()
+      + zero_copy_buf.as_ref().map(|buf| buf.len()).unwrap_or(0);
+    let maybe_op = match self.dispatcher.as_mut() {
+      Some(dispatcher) if !self.op_registry.contains(op_id) => {
+        Some(dispatcher(op_id, control_buf, zero_copy_buf))
+      }
+      _ => self.op_registry.call(op_id, control_buf, zero_copy_buf),
+    };
 
     let op = match maybe_op {
       Some(op) => op,
@@ -394,54 +1140,323 @@ impl Isolate {
       }
     };
 
+    let op_stat = op_stat_mut(&mut self.op_stats, op_id);
+    op_stat.calls += 1;
+    op_stat.bytes_received += bytes_received as u64;
+
     debug_assert_eq!(self.shared.size(), 0);
     match op {
       Op::Sync(buf) => {
+        op_stat_mut(&mut self.op_stats, op_id).bytes_sent += buf.len() as u64;
         // For sync messages, we always return the response via Deno.core.send's
         // return value. Sync messages ignore the op_id.
         let op_id = 0;
-        Some((op_id, buf))
+        Some((op_id, SyncResponse::Buf(buf)))
+      }
+      Op::SyncI32(value) => {
+        op_stat_mut(&mut self.op_stats, op_id).bytes_sent +=
+          std::mem::size_of::<i32>() as u64;
+        Some((0, SyncResponse::I32(value)))
       }
       Op::Async(fut) => {
-        let fut2 = fut.map(move |buf| (op_id, buf));
-        self.pending_ops.push(fut2.boxed_local());
-        self.have_unpolled_ops = true;
+        self.queue_op(scope, op_id, fut, false);
         None
       }
       Op::AsyncUnref(fut) => {
-        let fut2 = fut.map(move |buf| (op_id, buf));
-        self.pending_unref_ops.push(fut2.boxed_local());
-        self.have_unpolled_ops = true;
+        self.queue_op(scope, op_id, fut, true);
         None
       }
     }
   }
 
-  /// Executes traditional JavaScript code (traditional = not ES modules)
+  /// Queues the future of an async op for `poll()` to drive to completion.
+  fn queue_op<'s>(
+    &mut self,
+    scope: &mut impl v8::ToLocal<'s>,
+    op_id: OpId,
+    fut: OpAsyncFuture,
+    unref: bool,
+  ) {
+    // Isolates without realms don't pay for looking up the realm that sent
+    // the op.
+    let realm_id = if self.realms.is_empty() {
+      MAIN_REALM
+    } else {
+      self.current_realm(scope).unwrap_or(MAIN_REALM)
+    };
+
+    let key = self.next_pending_op;
+    self.next_pending_op += 1;
+    let (fut, abort_handle) = abortable(fut);
+    self
+      .pending_op_aborts
+      .insert(key, (op_id, realm_id, abort_handle));
+    let fut2 = fut.map(move |result| (op_id, key, result.ok()));
+    if unref {
+      self.pending_unref_ops.push(fut2.boxed_local());
+    } else {
+      self.pending_ops.push(fut2.boxed_local());
+    }
+    self.have_unpolled_ops = true;
+  }
+
+  /// Aborts op `op_id`, so that a mis-implemented op fails loudly instead of
+  /// leaving its promises pending forever, e.g. when its future never
+  /// completes or it returns a sync response that JavaScript doesn't expect.
+  ///
+  /// The pending futures of the op are dropped without their responses being
+  /// delivered, and an Error with `message` is passed to the handler
+  /// registered for the op with `Deno.core.setAbortHandler()`, which should
+  /// reject the op's pending promises. If there is no such handler, the error
+  /// is thrown and returned by this function instead. The error goes to each
+  /// realm that has pending calls of the op, or to the main realm if there
+  /// are none.
   ///
   /// ErrBox can be downcast to a type that exposes additional information about
   /// the V8 exception. By default this type is JSError, however it may be a
   /// different type if Isolate::set_js_error_create_fn() has been used.
-  pub fn execute(
-    &mut self,
-    js_filename: &str,
-    js_source: &str,
+  pub fn abort_op(&mut self, op_id: OpId, message: &str) -> Result<(), ErrBox> {
+    self.shared_init()?;
+    let mut realm_ids = vec![];
+    self
+      .pending_op_aborts
+      .retain(|_, (id, realm_id, abort_handle)| {
+        if *id == op_id {
+          abort_handle.abort();
+          if !realm_ids.contains(realm_id) {
+            realm_ids.push(*realm_id);
+          }
+        }
+        *id != op_id
+      });
+    if realm_ids.is_empty() {
+      realm_ids.push(MAIN_REALM);
+    }
+    realm_ids.sort();
+
+    let mut result = Ok(());
+    for realm_id in realm_ids {
+      let realm_result = self.abort_op_in_realm(realm_id, op_id, message);
+      if result.is_ok() {
+        result = realm_result;
+      }
+    }
+    result
+  }
+
+  /// Returns the realm whose context is the current one: `MAIN_REALM` for the
+  /// context the isolate was created with, or `None` for a context that
+  /// isn't a realm of this isolate.
+  pub(crate) fn current_realm<'s>(
+    &self,
+    scope: &mut impl v8::ToLocal<'s>,
+  ) -> Option<RealmId> {
+    let context = scope.get_current_context().unwrap();
+    if self.global_context.get(scope) == Some(context) {
+      return Some(MAIN_REALM);
+    }
+    self
+      .realms
+      .iter()
+      .position(|realm| realm.context.get(scope) == Some(context))
+      .map(|i| i + 1)
+  }
+
+  /// Passes the error of `Isolate::abort_op()` to the `Deno.core.recv()`
+  /// callback of the given realm.
+  fn abort_op_in_realm(
+    &mut self,
+    realm_id: RealmId,
+    op_id: OpId,
+    message: &str,
+  ) -> Result<(), ErrBox> {
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let (global_context, js_recv_cb) = realm_handles(
+      &self.global_context,
+      &self.js_recv_cb,
+      &self.realms,
+      realm_id,
+    )?;
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!global_context.is_empty());
+    let context = global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let global: v8::Local<v8::Value> = context.global(scope).into();
+    let js_recv_cb = match js_recv_cb.get(scope) {
+      Some(js_recv_cb) => js_recv_cb,
+      None => {
+        let message =
+          v8::String::new(scope, "Deno.core.recv has not been called.")
+            .unwrap();
+        let exception = v8::Exception::error(scope, message);
+        return exception_to_err_result(scope, exception, js_error_create_fn);
+      }
+    };
+    let op_id: v8::Local<v8::Value> =
+      v8::Integer::new(scope, op_id as i32).into();
+    let message = v8::String::new(scope, message).unwrap();
+    let error = v8::Exception::error(scope, message);
+
+    let mut try_catch = v8::TryCatch::new(scope);
+    let tc = try_catch.enter();
+
+    js_recv_cb.call(
+      scope,
+      context,
+      global,
+      &[op_id, v8::undefined(scope).into(), error],
+    );
+
+    match tc.exception() {
+      None => Ok(()),
+      Some(exception) => {
+        exception_to_err_result(scope, exception, js_error_create_fn)
+      }
+    }
+  }
+
+  /// Returns call counts and bytes transferred for every op that has been
+  /// dispatched, ordered by op id.
+  pub fn op_stats(&self) -> Vec<OpStat> {
+    let mut op_stats: Vec<OpStat> = self.op_stats.values().cloned().collect();
+    op_stats.sort_by_key(|op_stat| op_stat.op_id);
+    op_stats
+  }
+
+  /// Creates a new realm: a context with its own global object and its own
+  /// `Deno.core`, sharing the heap of this isolate. This is much cheaper than
+  /// creating another isolate.
+  ///
+  /// Each realm has its own `Deno.core.recv()` callback, shared queue and
+  /// fast channel, and the responses of async ops are delivered to the realm
+  /// that dispatched them. A realm is not a security boundary though: ops
+  /// dispatched from any realm go to the same ops and dispatcher, and
+  /// `setTimeout()` belongs to the main realm.
+  pub fn create_realm(&mut self) -> RealmId {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+
+    let context = bindings::initialize_context(scope);
+    let mut realm = Realm {
+      context: v8::Global::<v8::Context>::new(),
+      js_recv_cb: v8::Global::<v8::Function>::new(),
+      shared: SharedQueue::new(self.shared_queue_size),
+      shared_ab: v8::Global::<v8::SharedArrayBuffer>::new(),
+      fast_channel: None,
+      fast_channel_ab: v8::Global::<v8::SharedArrayBuffer>::new(),
+    };
+    realm.context.set(scope, context);
+
+    // The realm reads its own shared queue, so it needs its own copy of the
+    // JS for it. The built-in JS doesn't throw.
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+    let source =
+      v8::String::new(scope, include_str!("shared_queue.js")).unwrap();
+    let name = v8::String::new(scope, "shared_queue.js").unwrap();
+    let origin =
+      bindings::script_origin(scope, name, None, bindings::next_script_id());
+    let mut script =
+      v8::Script::compile(scope, context, source, Some(&origin)).unwrap();
+    script.run(scope, context).unwrap();
+
+    self.realms.push(realm);
+    self.realms.len()
+  }
+
+  /// Executes traditional JavaScript code (traditional = not ES modules)
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn execute(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<(), ErrBox> {
+    self.execute_in_realm(MAIN_REALM, js_filename, js_source)
+  }
+
+  /// Same as `Isolate::execute()`, but runs the code in the given realm.
+  ///
+  /// Returns an `UnknownRealm` error if `realm_id` was not returned by
+  /// `Isolate::create_realm()`.
+  pub fn execute_in_realm(
+    &mut self,
+    realm_id: RealmId,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<(), ErrBox> {
+    self.execute_script(realm_id, js_filename, js_source, 0, 0, None)
+  }
+
+  /// Same as `Isolate::execute()`, for code that starts at the given
+  /// zero-based line and column of `js_filename`, e.g. because the host
+  /// wrapped the file's contents in a function or prefixed a REPL prompt.
+  /// Positions in stack traces and errors then refer to the original file.
+  pub fn execute_with_offset(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+    line_offset: i32,
+    column_offset: i32,
+  ) -> Result<(), ErrBox> {
+    self.execute_script(
+      MAIN_REALM,
+      js_filename,
+      js_source,
+      line_offset,
+      column_offset,
+      None,
+    )
+  }
+
+  /// Compiles and runs a script in the given realm. The completion value is
+  /// stored in `completion_value`, if given.
+  fn execute_script(
+    &mut self,
+    realm_id: RealmId,
+    js_filename: &str,
+    js_source: &str,
+    line_offset: i32,
+    column_offset: i32,
+    completion_value: Option<&mut v8::Global<v8::Value>>,
   ) -> Result<(), ErrBox> {
-    self.shared_init();
+    self.shared_init()?;
 
     let js_error_create_fn = &*self.js_error_create_fn;
     let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let (global_context, _) = realm_handles(
+      &self.global_context,
+      &self.js_recv_cb,
+      &self.realms,
+      realm_id,
+    )?;
 
     let mut hs = v8::HandleScope::new(v8_isolate);
     let scope = hs.enter();
-    assert!(!self.global_context.is_empty());
-    let context = self.global_context.get(scope).unwrap();
+    assert!(!global_context.is_empty());
+    let context = global_context.get(scope).unwrap();
     let mut cs = v8::ContextScope::new(scope, context);
     let scope = cs.enter();
 
     let source = v8::String::new(scope, js_source).unwrap();
     let name = v8::String::new(scope, js_filename).unwrap();
-    let origin = bindings::script_origin(scope, name);
+    let source_map_url = bindings::source_map_url_from_source(js_source);
+    let origin = bindings::script_origin_with_offset(
+      scope,
+      name,
+      source_map_url,
+      bindings::next_script_id(),
+      line_offset,
+      column_offset,
+    );
 
     let mut try_catch = v8::TryCatch::new(scope);
     let tc = try_catch.enter();
@@ -456,7 +1471,12 @@ impl Isolate {
       };
 
     match script.run(scope, context) {
-      Some(_) => Ok(()),
+      Some(value) => {
+        if let Some(completion_value) = completion_value {
+          completion_value.set(scope, value);
+        }
+        Ok(())
+      }
       None => {
         assert!(tc.has_caught());
         let exception = tc.exception().unwrap();
@@ -465,734 +1485,2917 @@ impl Isolate {
     }
   }
 
-  /// Takes a snapshot. The isolate should have been created with will_snapshot
-  /// set to true.
+  /// Same as `Isolate::execute()`, but returns the completion value of the
+  /// script, i.e. the value of its last expression statement.
   ///
   /// ErrBox can be downcast to a type that exposes additional information about
   /// the V8 exception. By default this type is JSError, however it may be a
   /// different type if Isolate::set_js_error_create_fn() has been used.
-  pub fn snapshot(&mut self) -> v8::OwnedStartupData {
-    assert!(self.snapshot_creator.is_some());
+  pub fn execute_result(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<v8::Global<v8::Value>, ErrBox> {
+    let mut value = v8::Global::<v8::Value>::new();
+    self.execute_script(
+      MAIN_REALM,
+      js_filename,
+      js_source,
+      0,
+      0,
+      Some(&mut value),
+    )?;
+    Ok(value)
+  }
 
-    // Note: create_blob() method must not be called from within a HandleScope.
-    // The HandleScope created here is exited at the end of the block.
-    // TODO(piscisaureus): The rusty_v8 type system should enforce this.
-    {
-      let v8_isolate = self.v8_isolate.as_mut().unwrap();
-      let mut hs = v8::HandleScope::new(v8_isolate);
-      let scope = hs.enter();
-      self.global_context.reset(scope);
-    }
+  /// Same as `Isolate::execute_result()`, but serializes the completion value
+  /// to JSON. Returns None if the value cannot be represented as JSON, e.g.
+  /// if it is undefined or a function.
+  pub fn execute_result_json(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<Option<String>, ErrBox> {
+    let mut value = self.execute_result(js_filename, js_source)?;
 
-    let snapshot_creator = self.snapshot_creator.as_mut().unwrap();
-    let snapshot = snapshot_creator
-      .create_blob(v8::FunctionCodeHandling::Keep)
-      .unwrap();
-    self.has_snapshotted = true;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
 
-    snapshot
-  }
-}
+    let mut try_catch = v8::TryCatch::new(scope);
+    let _tc = try_catch.enter();
 
-impl Future for Isolate {
-  type Output = Result<(), ErrBox>;
+    let local = value.get(scope).unwrap();
+    value.reset(scope);
+    if bindings::is_json_undefined(local) {
+      return Ok(None);
+    }
+    Ok(
+      v8::json::stringify(context, local)
+        .map(|s| s.to_rust_string_lossy(scope)),
+    )
+  }
 
-  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-    let inner = self.get_mut();
-    inner.waker.register(cx.waker());
-    inner.shared_init();
+  /// Same as `Isolate::execute()`, but the source is compiled and evaluated
+  /// as an ES module with a module origin, so that strict mode, top-level
+  /// `await` and `import.meta` behave like they do for modules loaded by
+  /// `EsIsolate`. The module can't import other modules.
+  ///
+  /// With top-level await, the returned result only covers the evaluation up
+  /// to the first `await`.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn execute_module(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<(), ErrBox> {
+    self.shared_init()?;
 
-    let v8_isolate = inner.v8_isolate.as_mut().unwrap();
-    let js_error_create_fn = &*inner.js_error_create_fn;
-    let js_recv_cb = &inner.js_recv_cb;
-    let js_macrotask_cb = &inner.js_macrotask_cb;
-    let pending_promise_exceptions = &mut inner.pending_promise_exceptions;
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
 
     let mut hs = v8::HandleScope::new(v8_isolate);
     let scope = hs.enter();
-    let context = inner.global_context.get(scope).unwrap();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
     let mut cs = v8::ContextScope::new(scope, context);
     let scope = cs.enter();
 
-    check_promise_exceptions(
+    let source = v8::String::new(scope, js_source).unwrap();
+    let name = v8::String::new(scope, js_filename).unwrap();
+    let source_map_url = bindings::source_map_url_from_source(js_source);
+    let origin = bindings::module_origin(
       scope,
-      pending_promise_exceptions,
-      js_error_create_fn,
-    )?;
+      name,
+      source_map_url,
+      bindings::next_script_id(),
+    );
+    let source = v8::script_compiler::Source::new(source, &origin);
 
-    let mut overflow_response: Option<(OpId, Buf)> = None;
+    let mut try_catch = v8::TryCatch::new(scope);
+    let tc = try_catch.enter();
 
-    loop {
-      // Now handle actual ops.
-      inner.have_unpolled_ops = false;
-      #[allow(clippy::match_wild_err_arm)]
-      match select(&mut inner.pending_ops, &mut inner.pending_unref_ops)
-        .poll_next_unpin(cx)
-      {
-        Poll::Ready(None) => break,
-        Poll::Pending => break,
-        Poll::Ready(Some((op_id, buf))) => {
-          let successful_push = inner.shared.push(op_id, &buf);
-          if !successful_push {
-            // If we couldn't push the response to the shared queue, because
-            // there wasn't enough size, we will return the buffer via the
-            // legacy route, using the argument of deno_respond.
-            overflow_response = Some((op_id, buf));
-            break;
-          }
-        }
+    let mut module = match v8::script_compiler::compile_module(scope, source) {
+      Some(module) => module,
+      None => {
+        let exception = tc.exception().unwrap();
+        return exception_to_err_result(scope, exception, js_error_create_fn);
       }
-    }
-
-    if inner.shared.size() > 0 {
-      async_op_response(scope, None, js_recv_cb, js_error_create_fn)?;
-      // The other side should have shifted off all the messages.
-      assert_eq!(inner.shared.size(), 0);
-    }
+    };
 
-    if overflow_response.is_some() {
-      let (op_id, buf) = overflow_response.take().unwrap();
-      async_op_response(
-        scope,
-        Some((op_id, buf)),
-        js_recv_cb,
-        js_error_create_fn,
-      )?;
+    if module
+      .instantiate_module(context, bindings::no_imports_resolve_callback)
+      .is_none()
+    {
+      let exception = tc.exception().unwrap();
+      return exception_to_err_result(scope, exception, js_error_create_fn);
     }
 
-    drain_macrotasks(scope, js_macrotask_cb, js_error_create_fn)?;
-
-    check_promise_exceptions(
-      scope,
-      pending_promise_exceptions,
-      js_error_create_fn,
-    )?;
-
-    // We're idle if pending_ops is empty.
-    if inner.pending_ops.is_empty() {
-      Poll::Ready(Ok(()))
-    } else {
-      if inner.have_unpolled_ops {
-        inner.waker.wake();
+    module.evaluate(scope, context);
+    match module.get_status() {
+      v8::ModuleStatus::Errored => {
+        let exception = module.get_exception();
+        exception_to_err_result(scope, exception, js_error_create_fn)
       }
-      Poll::Pending
+      _ => Ok(()),
     }
   }
-}
-
-fn async_op_response<'s>(
-  scope: &mut impl v8::ToLocal<'s>,
-  maybe_buf: Option<(OpId, Box<[u8]>)>,
-  js_recv_cb: &v8::Global<v8::Function>,
-  js_error_create_fn: &JSErrorCreateFn,
-) -> Result<(), ErrBox> {
-  let context = scope.get_current_context().unwrap();
-  let global: v8::Local<v8::Value> = context.global(scope).into();
-  let js_recv_cb = js_recv_cb
-    .get(scope)
-    .expect("Deno.core.recv has not been called.");
 
-  // TODO(piscisaureus): properly integrate TryCatch in the scope chain.
-  let mut try_catch = v8::TryCatch::new(scope);
-  let tc = try_catch.enter();
+  /// Asks V8 to free as much memory as possible, which performs full garbage
+  /// collections.
+  pub fn low_memory_notification(&mut self) {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    v8_isolate.low_memory_notification();
+  }
 
-  match maybe_buf {
-    Some((op_id, buf)) => {
-      let op_id: v8::Local<v8::Value> =
-        v8::Integer::new(scope, op_id as i32).into();
-      let ui8: v8::Local<v8::Value> =
-        bindings::boxed_slice_to_uint8array(scope, buf).into();
-      js_recv_cb.call(scope, context, global, &[op_id, ui8])
+  /// Same as `Isolate::execute()`, but terminates the script if it hasn't
+  /// finished after `timeout`, in which case the returned error is a JSError
+  /// with `is_terminated` set. The isolate can be used again afterwards.
+  pub fn execute_with_timeout(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+    timeout: Duration,
+  ) -> Result<(), ErrBox> {
+    if self.watchdog.is_none() {
+      let v8_isolate_handle =
+        self.v8_isolate.as_mut().unwrap().thread_safe_handle();
+      self.watchdog = Some(Watchdog::new(v8_isolate_handle));
     }
-    None => js_recv_cb.call(scope, context, global, &[]),
-  };
 
-  match tc.exception() {
-    None => Ok(()),
-    Some(exception) => {
-      exception_to_err_result(scope, exception, js_error_create_fn)
-    }
-  }
-}
+    self.watchdog.as_ref().unwrap().arm(timeout);
+    let result = self.execute(js_filename, js_source);
+    let timed_out = self.watchdog.as_ref().unwrap().disarm();
 
-fn drain_macrotasks<'s>(
-  scope: &mut impl v8::ToLocal<'s>,
-  js_macrotask_cb: &v8::Global<v8::Function>,
-  js_error_create_fn: &JSErrorCreateFn,
-) -> Result<(), ErrBox> {
-  let context = scope.get_current_context().unwrap();
-  let global: v8::Local<v8::Value> = context.global(scope).into();
-  let js_macrotask_cb = js_macrotask_cb.get(scope);
-  if js_macrotask_cb.is_none() {
-    return Ok(());
+    if timed_out {
+      // The deadline may have passed just after the script finished, in which
+      // case termination is still pending. Either way, cancel it so the
+      // isolate can run scripts again.
+      self.cancel_terminate_execution();
+    }
+    result
   }
-  let js_macrotask_cb = js_macrotask_cb.unwrap();
 
-  // Repeatedly invoke macrotask callback until it returns true (done),
-  // such that ready microtasks would be automatically run before
-  // next macrotask is processed.
-  loop {
+  /// Cancels a termination requested with `terminate_execution()` on the
+  /// isolate's `thread_safe_handle()`, e.g. when a watchdog's timeout has
+  /// been disarmed after all. Scripts can run again afterwards. Returns false
+  /// if the isolate has been disposed.
+  pub fn cancel_terminate_execution(&mut self) -> bool {
+    self.heap_limit_reached = false;
+    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
+    // also be implemented on `struct Isolate`.
+    self
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .thread_safe_handle()
+      .cancel_terminate_execution()
+  }
+
+  /// Returns true while a termination is unwinding the JavaScript stack, i.e.
+  /// between `terminate_execution()` taking effect and the call that ran the
+  /// script returning. Hosts can check this, e.g. from an op, before
+  /// dispatching more work. Errors returned from `Isolate` methods have
+  /// already cancelled the termination, see `JSError::is_terminated`.
+  pub fn is_execution_terminating(&mut self) -> bool {
+    // TODO(piscisaureus): in rusty_v8, `is_execution_terminating()` should
+    // also be implemented on `struct Isolate`.
+    self
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .thread_safe_handle()
+      .is_execution_terminating()
+  }
+
+  /// Reads the property called `name` off the global object. Returns None if
+  /// it is undefined or if its getter throws.
+  pub fn get_global(&mut self, name: &str) -> Option<v8::Global<v8::Value>> {
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let mut try_catch = v8::TryCatch::new(scope);
+    let _tc = try_catch.enter();
+
+    let key = v8::String::new(scope, name).unwrap();
+    let value = context.global(scope).get(scope, context, key.into())?;
+    if value.is_undefined() {
+      return None;
+    }
+    Some(v8::Global::new_from(scope, value))
+  }
+
+  /// Same as `Isolate::get_global()`, but serializes the value to JSON.
+  /// Returns None if the value cannot be represented as JSON, e.g. because
+  /// it is a function.
+  pub fn get_global_json(&mut self, name: &str) -> Option<String> {
+    let mut value = self.get_global(name)?;
+
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let mut try_catch = v8::TryCatch::new(scope);
+    let _tc = try_catch.enter();
+
+    let local = value.get(scope).unwrap();
+    value.reset(scope);
+    if bindings::is_json_undefined(local) {
+      return None;
+    }
+    v8::json::stringify(context, local).map(|s| s.to_rust_string_lossy(scope))
+  }
+
+  /// Parses `value_json` and assigns the result to the property called `name`
+  /// of the global object, overwriting any existing value. Useful to inject
+  /// configuration before running user scripts.
+  ///
+  /// Invalid JSON, or a property that can't be written (e.g. because the
+  /// global object is frozen), results in an error.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn set_global(
+    &mut self,
+    name: &str,
+    value_json: &str,
+  ) -> Result<(), ErrBox> {
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
     let mut try_catch = v8::TryCatch::new(scope);
     let tc = try_catch.enter();
 
-    let is_done = js_macrotask_cb.call(scope, context, global, &[]);
+    let value_json = v8::String::new(scope, value_json).unwrap();
+    let value = match v8::json::parse(context, value_json) {
+      Some(value) => value,
+      None => {
+        let exception = tc.exception().unwrap();
+        return exception_to_err_result(scope, exception, js_error_create_fn);
+      }
+    };
 
-    if let Some(exception) = tc.exception() {
-      return exception_to_err_result(scope, exception, js_error_create_fn);
+    let key = v8::String::new(scope, name).unwrap();
+    match context.global(scope).set(context, key.into(), value) {
+      Some(true) => Ok(()),
+      Some(false) => {
+        // Assignments outside of strict mode fail silently.
+        let message = v8::String::new(
+          scope,
+          &format!("Cannot assign to read only global \"{}\"", name),
+        )
+        .unwrap();
+        let exception = v8::Exception::type_error(scope, message);
+        exception_to_err_result(scope, exception, js_error_create_fn)
+      }
+      None => {
+        let exception = tc.exception().unwrap();
+        exception_to_err_result(scope, exception, js_error_create_fn)
+      }
     }
+  }
 
-    let is_done = is_done.unwrap();
-    if is_done.is_true() {
-      break;
+  /// Installs `recv` as the function that async op responses are delivered
+  /// to, as if JavaScript had called `Deno.core.recv(recv)`, replacing any
+  /// function registered before, e.g. to swap it during a hot reload.
+  ///
+  /// The function is called with an op id and a Uint8Array for responses
+  /// delivered with `Isolate::respond_zero_copy()`, and without arguments
+  /// when responses are waiting in the shared queue. Replacing it bypasses
+  /// the shared queue handling of the built-in JavaScript, so `recv` has to
+  /// drain `Deno.core.sharedQueue` itself.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn set_recv(
+    &mut self,
+    mut recv: v8::Global<v8::Value>,
+  ) -> Result<(), ErrBox> {
+    // The built-in JavaScript registers its own recv function when it runs.
+    self.shared_init()?;
+
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    let value = recv.get(scope).expect("Empty recv handle");
+    recv.reset(scope);
+    match v8::Local::<v8::Function>::try_from(value) {
+      Ok(recv_fn) => {
+        self.js_recv_cb.set(scope, recv_fn);
+        Ok(())
+      }
+      Err(_) => {
+        let message =
+          v8::String::new(scope, "recv must be a function").unwrap();
+        let exception = v8::Exception::type_error(scope, message);
+        exception_to_err_result(scope, exception, js_error_create_fn)
+      }
     }
   }
 
-  Ok(())
-}
+  /// Releases an isolate that was created with will_snapshot set to true,
+  /// without taking a snapshot. Such an isolate is leaked when it's simply
+  /// dropped before `Isolate::snapshot()` was called, because V8 asserts
+  /// that a SnapshotCreator has created a snapshot before it's destroyed.
+  /// This creates a throwaway snapshot to satisfy the assert, so that
+  /// everything can be freed.
+  pub fn abort_snapshot(mut self: Box<Self>) {
+    assert!(self.snapshot_creator.is_some());
+    if !self.has_snapshotted {
+      drop(self.create_snapshot());
+    }
+  }
 
-pub(crate) fn attach_handle_to_error(
-  scope: &mut impl v8::InIsolate,
-  err: ErrBox,
-  handle: v8::Local<v8::Value>,
-) -> ErrBox {
-  ErrWithV8Handle::new(scope, err, handle).into()
+  /// Runs a warm-up script before a snapshot is taken. Functions called by the
+  /// script are compiled, and because compiled code is kept when the snapshot
+  /// is created, isolates created from the snapshot don't have to compile
+  /// them lazily again. The isolate should have been created with
+  /// will_snapshot set to true.
+  ///
+  /// The script runs in the same context that is snapshotted, so any state it
+  /// leaves behind ends up in the snapshot. It must not leave work pending
+  /// that refers to host state, like unresolved async ops. Handles held by
+  /// the isolate itself, e.g. the callback registered with
+  /// `Deno.core.recv()`, are released by `Isolate::snapshot()`.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  pub fn snapshot_warm_up(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<(), ErrBox> {
+    assert!(self.snapshot_creator.is_some());
+    self.execute(js_filename, js_source)
+  }
+
+  /// Takes a snapshot. The isolate should have been created with will_snapshot
+  /// set to true.
+  ///
+  /// ErrBox can be downcast to a type that exposes additional information about
+  /// the V8 exception. By default this type is JSError, however it may be a
+  /// different type if Isolate::set_js_error_create_fn() has been used.
+  ///
+  /// Fails, without taking the snapshot, if a promise rejection has not been
+  /// handled yet: it would otherwise be lost, because the handles to pending
+  /// rejections can't be snapshotted. The rejection is returned as the error.
+  pub fn snapshot(&mut self) -> Result<v8::OwnedStartupData, ErrBox> {
+    assert!(self.snapshot_creator.is_some());
+    self.check_promise_exceptions()?;
+    Ok(self.create_snapshot())
+  }
+
+  /// Returns the first pending promise rejection, if any, as an error.
+  pub(crate) fn check_promise_exceptions(&mut self) -> Result<(), ErrBox> {
+    let js_error_create_fn = &*self.js_error_create_fn;
+    let v8_isolate = self.v8_isolate.as_mut().unwrap();
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    assert!(!self.global_context.is_empty());
+    let context = self.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    check_promise_exceptions(
+      scope,
+      &mut self.pending_promise_exceptions,
+      js_error_create_fn,
+    )
+  }
+
+  fn create_snapshot(&mut self) -> v8::OwnedStartupData {
+    // Note: create_blob() method must not be called from within a HandleScope.
+    // The HandleScope created here is exited at the end of the block.
+    // TODO(piscisaureus): The rusty_v8 type system should enforce this.
+    {
+      let v8_isolate = self.v8_isolate.as_mut().unwrap();
+      let mut hs = v8::HandleScope::new(v8_isolate);
+      let scope = hs.enter();
+      self.global_context.reset(scope);
+      // Realms are not part of the snapshot.
+      for mut realm in self.realms.drain(..) {
+        realm.context.reset(scope);
+        realm.js_recv_cb.reset(scope);
+        realm.shared_ab.reset(scope);
+        realm.fast_channel_ab.reset(scope);
+      }
+      for key in self.string_keys.values_mut() {
+        key.reset(scope);
+      }
+      // V8 refuses to create a snapshot while global handles are alive, and
+      // these may have been set by scripts, e.g. by a warm-up script.
+      self.shared_ab.reset(scope);
+      self.fast_channel_ab.reset(scope);
+      self.js_recv_cb.reset(scope);
+      self.js_macrotask_cb.reset(scope);
+      for (_, mut handle) in self.pending_promise_exceptions.drain() {
+        handle.reset(scope);
+      }
+      for (_, mut timer) in self.pending_timers.drain() {
+        timer.callback.reset(scope);
+      }
+      self.elapsed_timers.clear();
+    }
+
+    let snapshot_creator = self.snapshot_creator.as_mut().unwrap();
+    let snapshot = snapshot_creator
+      .create_blob(v8::FunctionCodeHandling::Keep)
+      .unwrap();
+    self.has_snapshotted = true;
+
+    snapshot
+  }
 }
 
-pub(crate) fn exception_to_err_result<'s, T>(
-  scope: &mut impl v8::ToLocal<'s>,
-  exception: v8::Local<v8::Value>,
-  js_error_create_fn: &JSErrorCreateFn,
-) -> Result<T, ErrBox> {
-  // TODO(piscisaureus): in rusty_v8, `is_execution_terminating()` should
-  // also be implemented on `struct Isolate`.
-  let is_terminating_exception = scope
-    .isolate()
-    .thread_safe_handle()
-    .is_execution_terminating();
-  let mut exception = exception;
+impl Future for Isolate {
+  type Output = Result<(), ErrBox>;
 
-  if is_terminating_exception {
-    // TerminateExecution was called. Cancel exception termination so that the
-    // exception can be created..
-    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
-    // also be implemented on `struct Isolate`.
-    scope
-      .isolate()
-      .thread_safe_handle()
-      .cancel_terminate_execution();
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    let inner = self.get_mut();
+    inner.waker.register(cx.waker());
+    inner.shared_init()?;
 
-    // Maybe make a new exception object.
-    if exception.is_null_or_undefined() {
-      let message = v8::String::new(scope, "execution terminated").unwrap();
-      exception = v8::Exception::error(scope, message);
+    let v8_isolate = inner.v8_isolate.as_mut().unwrap();
+    let js_error_create_fn = &*inner.js_error_create_fn;
+    let js_recv_cb = &inner.js_recv_cb;
+    let js_macrotask_cb = &inner.js_macrotask_cb;
+    let pending_promise_exceptions = &mut inner.pending_promise_exceptions;
+
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    let context = inner.global_context.get(scope).unwrap();
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    check_promise_exceptions(
+      scope,
+      pending_promise_exceptions,
+      js_error_create_fn,
+    )?;
+
+    let mut overflow_response: Option<(RealmId, OpId, Buf)> = None;
+
+    loop {
+      // Now handle actual ops.
+      inner.have_unpolled_ops = false;
+      #[allow(clippy::match_wild_err_arm)]
+      match select(&mut inner.pending_ops, &mut inner.pending_unref_ops)
+        .poll_next_unpin(cx)
+      {
+        Poll::Ready(None) => break,
+        Poll::Pending => break,
+        // The op was aborted with `Isolate::abort_op()`.
+        Poll::Ready(Some((_, _, None))) => {}
+        Poll::Ready(Some((op_id, key, Some(buf))))
+          if Some(op_id) == inner.timer_op_id =>
+        {
+          inner.pending_op_aborts.remove(&key);
+          op_stat_mut(&mut inner.op_stats, op_id).bytes_sent +=
+            buf.len() as u64;
+          let timer_id: Result<[u8; 4], _> = (&*buf).try_into();
+          match timer_id {
+            Ok(timer_id) => {
+              inner.elapsed_timers.push(u32::from_le_bytes(timer_id))
+            }
+            Err(_) => {
+              let message =
+                v8::String::new(scope, "Bad timer op response").unwrap();
+              let exception = v8::Exception::type_error(scope, message);
+              return Poll::Ready(exception_to_err_result(
+                scope,
+                exception,
+                js_error_create_fn,
+              ));
+            }
+          }
+        }
+        Poll::Ready(Some((op_id, key, Some(buf)))) => {
+          // The response goes to the realm that dispatched the op.
+          let realm_id = match inner.pending_op_aborts.remove(&key) {
+            Some((_, realm_id, _)) => realm_id,
+            None => MAIN_REALM,
+          };
+          op_stat_mut(&mut inner.op_stats, op_id).bytes_sent +=
+            buf.len() as u64;
+          let shared = if realm_id == MAIN_REALM {
+            &mut inner.shared
+          } else {
+            match inner.realms.get_mut(realm_id - 1) {
+              Some(realm) => &mut realm.shared,
+              // The realm was released for a snapshot.
+              None => continue,
+            }
+          };
+          let successful_push = shared.push(op_id, &buf);
+          if !successful_push {
+            // If we couldn't push the response to the shared queue, because
+            // there wasn't enough size, we will return the buffer via the
+            // legacy route, using the argument of deno_respond.
+            overflow_response = Some((realm_id, op_id, buf));
+            break;
+          }
+        }
+      }
+    }
+
+    if inner.shared.size() > 0 {
+      async_op_response(scope, None, js_recv_cb, js_error_create_fn)?;
+      // The other side should have shifted off all the messages.
+      assert_eq!(inner.shared.size(), 0);
+    }
+
+    for realm in inner.realms.iter() {
+      if realm.shared.size() > 0 {
+        let context = realm.context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        async_op_response(scope, None, &realm.js_recv_cb, js_error_create_fn)?;
+        assert_eq!(realm.shared.size(), 0);
+      }
     }
+
+    if let Some((realm_id, op_id, buf)) = overflow_response.take() {
+      let (global_context, js_recv_cb) = realm_handles(
+        &inner.global_context,
+        js_recv_cb,
+        &inner.realms,
+        realm_id,
+      )?;
+      let context = global_context.get(scope).unwrap();
+      let mut cs = v8::ContextScope::new(scope, context);
+      let scope = cs.enter();
+      let backing_store =
+        v8::ArrayBuffer::new_backing_store_from_boxed_slice(buf).make_shared();
+      async_op_response(
+        scope,
+        Some((op_id, backing_store)),
+        js_recv_cb,
+        js_error_create_fn,
+      )?;
+    }
+
+    fire_timers(
+      scope,
+      &mut inner.pending_timers,
+      &mut inner.elapsed_timers,
+      js_error_create_fn,
+    )?;
+
+    drain_macrotasks(scope, js_macrotask_cb, js_error_create_fn)?;
+
+    check_promise_exceptions(
+      scope,
+      pending_promise_exceptions,
+      js_error_create_fn,
+    )?;
+
+    // We're idle if pending_ops is empty.
+    if inner.pending_ops.is_empty() {
+      Poll::Ready(Ok(()))
+    } else {
+      if inner.have_unpolled_ops {
+        inner.waker.wake();
+      }
+      Poll::Pending
+    }
+  }
+}
+
+fn async_op_response<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  maybe_buf: Option<(OpId, v8::SharedRef<v8::BackingStore>)>,
+  js_recv_cb: &v8::Global<v8::Function>,
+  js_error_create_fn: &JSErrorCreateFn,
+) -> Result<(), ErrBox> {
+  let context = scope.get_current_context().unwrap();
+  let global: v8::Local<v8::Value> = context.global(scope).into();
+  let js_recv_cb = match js_recv_cb.get(scope) {
+    Some(js_recv_cb) => js_recv_cb,
+    None => {
+      let message =
+        v8::String::new(scope, "Deno.core.recv has not been called.").unwrap();
+      let exception = v8::Exception::error(scope, message);
+      return exception_to_err_result(scope, exception, js_error_create_fn);
+    }
+  };
+
+  // TODO(piscisaureus): properly integrate TryCatch in the scope chain.
+  let mut try_catch = v8::TryCatch::new(scope);
+  let tc = try_catch.enter();
+
+  match maybe_buf {
+    Some((op_id, backing_store)) => {
+      let op_id: v8::Local<v8::Value> =
+        v8::Integer::new(scope, op_id as i32).into();
+      let ui8: v8::Local<v8::Value> =
+        bindings::backing_store_to_uint8array(scope, backing_store).into();
+      js_recv_cb.call(scope, context, global, &[op_id, ui8])
+    }
+    None => js_recv_cb.call(scope, context, global, &[]),
+  };
+
+  match tc.exception() {
+    None => Ok(()),
+    Some(exception) => {
+      exception_to_err_result(scope, exception, js_error_create_fn)
+    }
+  }
+}
+
+/// Returns the context and the `Deno.core.recv()` callback of a realm.
+fn realm_handles<'a>(
+  global_context: &'a v8::Global<v8::Context>,
+  js_recv_cb: &'a v8::Global<v8::Function>,
+  realms: &'a [Realm],
+  realm_id: RealmId,
+) -> Result<(&'a v8::Global<v8::Context>, &'a v8::Global<v8::Function>), ErrBox>
+{
+  if realm_id == MAIN_REALM {
+    return Ok((global_context, js_recv_cb));
+  }
+  match realms.get(realm_id - 1) {
+    Some(realm) => Ok((&realm.context, &realm.js_recv_cb)),
+    None => Err(UnknownRealm(realm_id).into()),
+  }
+}
+
+fn new_fast_channel(size: usize) -> v8::SharedRef<v8::BackingStore> {
+  let buf = vec![0; size].into_boxed_slice();
+  v8::SharedArrayBuffer::new_backing_store_from_boxed_slice(buf).make_shared()
+}
+
+fn op_stat_mut(
+  op_stats: &mut HashMap<OpId, OpStat>,
+  op_id: OpId,
+) -> &mut OpStat {
+  op_stats.entry(op_id).or_insert_with(|| OpStat {
+    op_id,
+    ..Default::default()
+  })
+}
+
+/// Calls the callbacks of the timers whose op has resolved, earliest deadline
+/// first. Cleared timers are skipped. If a callback throws, the timers that
+/// haven't fired yet stay in `elapsed_timers` and fire on the next poll.
+fn fire_timers<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  pending_timers: &mut HashMap<u32, PendingTimer>,
+  elapsed_timers: &mut Vec<u32>,
+  js_error_create_fn: &JSErrorCreateFn,
+) -> Result<(), ErrBox> {
+  elapsed_timers.retain(|id| pending_timers.contains_key(id));
+  // Latest deadline first, so that the next timer is popped off the end.
+  elapsed_timers.sort_by_key(|id| Reverse((pending_timers[id].deadline, *id)));
+
+  let context = scope.get_current_context().unwrap();
+  let global: v8::Local<v8::Value> = context.global(scope).into();
+
+  while let Some(id) = elapsed_timers.pop() {
+    // An earlier callback may have cleared this timer.
+    let mut timer = match pending_timers.remove(&id) {
+      Some(timer) => timer,
+      None => continue,
+    };
+    let callback = timer.callback.get(scope).unwrap();
+    timer.callback.reset(scope);
+
+    let mut try_catch = v8::TryCatch::new(scope);
+    let tc = try_catch.enter();
+
+    callback.call(scope, context, global, &[]);
+
+    if let Some(exception) = tc.exception() {
+      return exception_to_err_result(scope, exception, js_error_create_fn);
+    }
+  }
+
+  Ok(())
+}
+
+fn drain_macrotasks<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  js_macrotask_cb: &v8::Global<v8::Function>,
+  js_error_create_fn: &JSErrorCreateFn,
+) -> Result<(), ErrBox> {
+  let context = scope.get_current_context().unwrap();
+  let global: v8::Local<v8::Value> = context.global(scope).into();
+  let js_macrotask_cb = js_macrotask_cb.get(scope);
+  if js_macrotask_cb.is_none() {
+    return Ok(());
+  }
+  let js_macrotask_cb = js_macrotask_cb.unwrap();
+
+  // Repeatedly invoke macrotask callback until it returns true (done),
+  // such that ready microtasks would be automatically run before
+  // next macrotask is processed.
+  loop {
+    let mut try_catch = v8::TryCatch::new(scope);
+    let tc = try_catch.enter();
+
+    let is_done = js_macrotask_cb.call(scope, context, global, &[]);
+
+    if let Some(exception) = tc.exception() {
+      return exception_to_err_result(scope, exception, js_error_create_fn);
+    }
+
+    let is_done = is_done.unwrap();
+    if is_done.is_true() {
+      break;
+    }
+  }
+
+  Ok(())
+}
+
+extern "C" fn near_heap_limit_callback(
+  data: *mut c_void,
+  current_heap_limit: usize,
+  initial_heap_limit: usize,
+) -> usize {
+  let core_isolate = unsafe { &mut *(data as *mut Isolate) };
+  match core_isolate.near_heap_limit_callback.as_mut() {
+    Some(callback) => callback(current_heap_limit, initial_heap_limit),
+    None => {
+      // Set with `IsolateBuilder::terminate_on_heap_limit()`.
+      core_isolate.heap_limit_reached = true;
+      let v8_isolate = core_isolate.v8_isolate.as_mut().unwrap();
+      v8_isolate.thread_safe_handle().terminate_execution();
+      // Leave enough room for the termination to be processed.
+      current_heap_limit * 2
+    }
+  }
+}
+
+pub(crate) fn attach_handle_to_error(
+  scope: &mut impl v8::InIsolate,
+  err: ErrBox,
+  handle: v8::Local<v8::Value>,
+) -> ErrBox {
+  ErrWithV8Handle::new(scope, err, handle).into()
+}
+
+pub(crate) fn exception_to_err_result<'s, T>(
+  scope: &mut impl v8::ToLocal<'s>,
+  exception: v8::Local<v8::Value>,
+  js_error_create_fn: &JSErrorCreateFn,
+) -> Result<T, ErrBox> {
+  // TODO(piscisaureus): in rusty_v8, `is_execution_terminating()` should
+  // also be implemented on `struct Isolate`.
+  let is_terminating_exception = scope
+    .isolate()
+    .thread_safe_handle()
+    .is_execution_terminating();
+  let mut exception = exception;
+
+  if is_terminating_exception {
+    // TerminateExecution was called. Cancel exception termination so that the
+    // exception can be created..
+    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
+    // also be implemented on `struct Isolate`.
+    scope
+      .isolate()
+      .thread_safe_handle()
+      .cancel_terminate_execution();
+
+    // Maybe make a new exception object. An exception that was already in
+    // flight is preserved.
+    if exception.is_null_or_undefined() {
+      let message = v8::String::new(scope, "execution terminated").unwrap();
+      exception = v8::Exception::error(scope, message);
+    }
+  }
+
+  let deno_isolate = scope.isolate().get_data(0) as *mut Isolate;
+  let heap_limit_reached = is_terminating_exception
+    && !deno_isolate.is_null()
+    && unsafe { (*deno_isolate).heap_limit_reached };
+
+  let js_error = if heap_limit_reached {
+    // Only this termination was caused by the heap limit.
+    unsafe { (*deno_isolate).heap_limit_reached = false };
+    HeapLimitExceeded.into()
+  } else {
+    let mut js_error = JSError::from_v8_exception(scope, exception);
+    js_error.is_terminated = is_terminating_exception;
+    (js_error_create_fn)(js_error)
+  };
+
+  if is_terminating_exception {
+    // Re-enable exception termination.
+    // TODO(piscisaureus): in rusty_v8, `terminate_execution()` should also
+    // be implemented on `struct Isolate`.
+    scope.isolate().thread_safe_handle().terminate_execution();
+  }
+
+  Err(js_error)
+}
+
+fn check_promise_exceptions<'s>(
+  scope: &mut impl v8::ToLocal<'s>,
+  pending_promise_exceptions: &mut HashMap<i32, v8::Global<v8::Value>>,
+  js_error_create_fn: &JSErrorCreateFn,
+) -> Result<(), ErrBox> {
+  if let Some(&key) = pending_promise_exceptions.keys().next() {
+    let handle = pending_promise_exceptions.remove(&key).unwrap();
+    let exception = handle.get(scope).expect("empty error handle");
+    exception_to_err_result(scope, exception, js_error_create_fn)
+  } else {
+    Ok(())
+  }
+}
+
+pub fn js_check<T>(r: Result<T, ErrBox>) -> T {
+  if let Err(e) = r {
+    panic!(e.to_string());
+  }
+  r.unwrap()
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use futures::future::lazy;
+  use std::ops::FnOnce;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  pub fn run_in_task<F>(f: F)
+  where
+    F: FnOnce(&mut Context) + Send + 'static,
+  {
+    futures::executor::block_on(lazy(move |cx| f(cx)));
+  }
+
+  fn poll_until_ready<F>(future: &mut F, max_poll_count: usize) -> F::Output
+  where
+    F: Future + Unpin,
+  {
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    for _ in 0..max_poll_count {
+      match future.poll_unpin(&mut cx) {
+        Poll::Pending => continue,
+        Poll::Ready(val) => return val,
+      }
+    }
+    panic!(
+      "Isolate still not ready after polling {} times.",
+      max_poll_count
+    )
+  }
+
+  pub enum Mode {
+    Async,
+    AsyncUnref,
+    OverflowReqSync,
+    OverflowResSync,
+    OverflowReqAsync,
+    OverflowResAsync,
+  }
+
+  pub fn setup(mode: Mode) -> (Box<Isolate>, Arc<AtomicUsize>) {
+    let dispatch_count = Arc::new(AtomicUsize::new(0));
+    let dispatch_count_ = dispatch_count.clone();
+
+    let mut isolate = Isolate::new(StartupData::None, false);
+
+    let dispatcher =
+      move |control: &[u8], _zero_copy: Option<ZeroCopyBuf>| -> Op {
+        dispatch_count_.fetch_add(1, Ordering::Relaxed);
+        match mode {
+          Mode::Async => {
+            assert_eq!(control.len(), 1);
+            assert_eq!(control[0], 42);
+            let buf = vec![43u8].into_boxed_slice();
+            Op::Async(futures::future::ready(buf).boxed())
+          }
+          Mode::AsyncUnref => {
+            assert_eq!(control.len(), 1);
+            assert_eq!(control[0], 42);
+            let fut = async {
+              // This future never finish.
+              futures::future::pending::<()>().await;
+              vec![43u8].into_boxed_slice()
+            };
+            Op::AsyncUnref(fut.boxed())
+          }
+          Mode::OverflowReqSync => {
+            assert_eq!(control.len(), 100 * 1024 * 1024);
+            let buf = vec![43u8].into_boxed_slice();
+            Op::Sync(buf)
+          }
+          Mode::OverflowResSync => {
+            assert_eq!(control.len(), 1);
+            assert_eq!(control[0], 42);
+            let mut vec = Vec::<u8>::new();
+            vec.resize(100 * 1024 * 1024, 0);
+            vec[0] = 99;
+            let buf = vec.into_boxed_slice();
+            Op::Sync(buf)
+          }
+          Mode::OverflowReqAsync => {
+            assert_eq!(control.len(), 100 * 1024 * 1024);
+            let buf = vec![43u8].into_boxed_slice();
+            Op::Async(futures::future::ready(buf).boxed())
+          }
+          Mode::OverflowResAsync => {
+            assert_eq!(control.len(), 1);
+            assert_eq!(control[0], 42);
+            let mut vec = Vec::<u8>::new();
+            vec.resize(100 * 1024 * 1024, 0);
+            vec[0] = 4;
+            let buf = vec.into_boxed_slice();
+            Op::Async(futures::future::ready(buf).boxed())
+          }
+        }
+      };
+
+    isolate.register_op("test", dispatcher);
+
+    js_check(isolate.execute(
+      "setup.js",
+      r#"
+        function assert(cond) {
+          if (!cond) {
+            throw Error("assert");
+          }
+        }
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 0);
+    (isolate, dispatch_count)
+  }
+
+  #[test]
+  fn test_dispatch() {
+    let (mut isolate, dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "filename.js",
+      r#"
+        let control = new Uint8Array([42]);
+        Deno.core.send(1, control);
+        async function main() {
+          Deno.core.send(1, control);
+        }
+        main();
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
+  }
+
+  #[test]
+  fn test_poll_async_delayed_ops() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::Async);
+
+      js_check(isolate.execute(
+        "setup2.js",
+        r#"
+         let nrecv = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           nrecv++;
+         });
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 0);
+      js_check(isolate.execute(
+        "check1.js",
+        r#"
+         assert(nrecv == 0);
+         let control = new Uint8Array([42]);
+         Deno.core.send(1, control);
+         assert(nrecv == 0);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      js_check(isolate.execute(
+        "check2.js",
+        r#"
+         assert(nrecv == 1);
+         Deno.core.send(1, control);
+         assert(nrecv == 1);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+      js_check(isolate.execute("check3.js", "assert(nrecv == 2)"));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
+      // We are idle, so the next poll should be the last.
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+    });
+  }
+
+  #[test]
+  fn test_poll_async_optional_ops() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::AsyncUnref);
+      js_check(isolate.execute(
+        "check1.js",
+        r#"
+          Deno.core.setAsyncHandler(1, (buf) => {
+            // This handler will never be called
+            assert(false);
+          });
+          let control = new Uint8Array([42]);
+          Deno.core.send(1, control);
+        "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      // The above op never finish, but isolate can finish
+      // because the op is an unreffed async op.
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+    })
+  }
+
+  #[test]
+  fn terminate_execution() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    // TODO(piscisaureus): in rusty_v8, the `thread_safe_handle()` method
+    // should not require a mutable reference to `struct rusty_v8::Isolate`.
+    let v8_isolate_handle =
+      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+
+    let terminator_thread = std::thread::spawn(move || {
+      // allow deno to boot and run
+      std::thread::sleep(std::time::Duration::from_millis(100));
+
+      // terminate execution
+      let ok = v8_isolate_handle.terminate_execution();
+      assert!(ok);
+    });
+
+    // Rn an infinite loop, which should be terminated.
+    match isolate.execute("infinite_loop.js", "for(;;) {}") {
+      Ok(_) => panic!("execution should be terminated"),
+      Err(e) => {
+        assert_eq!(e.to_string(), "Uncaught Error: execution terminated");
+        let js_error = e.downcast::<JSError>().unwrap();
+        assert!(js_error.is_terminated);
+      }
+    };
+
+    // Cancel the execution-terminating exception in order to allow script
+    // execution again.
+    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
+    // also be implemented on `struct Isolate`.
+    let ok = isolate
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .thread_safe_handle()
+      .cancel_terminate_execution();
+    assert!(ok);
+
+    // Verify that the isolate usable again.
+    isolate
+      .execute("simple.js", "1 + 1")
+      .expect("execution should be possible again");
+
+    terminator_thread.join().unwrap();
+  }
+
+  #[test]
+  fn cancel_terminate_execution() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let v8_isolate_handle =
+      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+
+    // Termination that is armed and then cancelled doesn't stop the script.
+    assert!(v8_isolate_handle.terminate_execution());
+    assert!(isolate.cancel_terminate_execution());
+    js_check(isolate.execute("simple.js", "1 + 1"));
+  }
+
+  #[test]
+  fn is_execution_terminating() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    assert!(!isolate.is_execution_terminating());
+
+    let v8_isolate_handle =
+      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+    assert!(v8_isolate_handle.terminate_execution());
+    let err = isolate.execute("loop.js", "for(;;) {}").unwrap_err();
+    assert!(err.downcast::<JSError>().unwrap().is_terminated);
+    // The error is reported once the termination has been cancelled.
+    assert!(!isolate.is_execution_terminating());
+  }
+
+  #[test]
+  fn execute_with_timeout() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let timeout = Duration::from_millis(100);
+    let err = isolate
+      .execute_with_timeout("infinite_loop.js", "for(;;) {}", timeout)
+      .unwrap_err();
+    assert!(err.downcast::<JSError>().unwrap().is_terminated);
+
+    // The isolate is usable again.
+    js_check(isolate.execute_with_timeout(
+      "simple.js",
+      "1 + 1",
+      Duration::from_secs(10),
+    ));
+
+    // The watchdog thread is reused.
+    let err = isolate
+      .execute_with_timeout("infinite_loop.js", "for(;;) {}", timeout)
+      .unwrap_err();
+    assert!(err.downcast::<JSError>().unwrap().is_terminated);
+  }
+
+  #[test]
+  fn dangling_shared_isolate() {
+    let v8_isolate_handle = {
+      // isolate is dropped at the end of this block
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      // TODO(piscisaureus): in rusty_v8, the `thread_safe_handle()` method
+      // should not require a mutable reference to `struct rusty_v8::Isolate`.
+      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle()
+    };
+
+    // this should not SEGFAULT
+    v8_isolate_handle.terminate_execution();
+  }
+
+  #[test]
+  fn overflow_req_sync() {
+    let (mut isolate, dispatch_count) = setup(Mode::OverflowReqSync);
+    js_check(isolate.execute(
+      "overflow_req_sync.js",
+      r#"
+        let asyncRecv = 0;
+        Deno.core.setAsyncHandler(1, (buf) => { asyncRecv++ });
+        // Large message that will overflow the shared space.
+        let control = new Uint8Array(100 * 1024 * 1024);
+        let response = Deno.core.dispatch(1, control);
+        assert(response instanceof Uint8Array);
+        assert(response.length == 1);
+        assert(response[0] == 43);
+        assert(asyncRecv == 0);
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+  }
+
+  #[test]
+  fn overflow_res_sync() {
+    // TODO(ry) This test is quite slow due to memcpy-ing 100MB into JS. We
+    // should optimize this.
+    let (mut isolate, dispatch_count) = setup(Mode::OverflowResSync);
+    js_check(isolate.execute(
+      "overflow_res_sync.js",
+      r#"
+        let asyncRecv = 0;
+        Deno.core.setAsyncHandler(1, (buf) => { asyncRecv++ });
+        // Large message that will overflow the shared space.
+        let control = new Uint8Array([42]);
+        let response = Deno.core.dispatch(1, control);
+        assert(response instanceof Uint8Array);
+        assert(response.length == 100 * 1024 * 1024);
+        assert(response[0] == 99);
+        assert(asyncRecv == 0);
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+  }
+
+  #[test]
+  fn overflow_req_async() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::OverflowReqAsync);
+      js_check(isolate.execute(
+        "overflow_req_async.js",
+        r#"
+         let asyncRecv = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           assert(buf.byteLength === 1);
+           assert(buf[0] === 43);
+           asyncRecv++;
+         });
+         // Large message that will overflow the shared space.
+         let control = new Uint8Array(100 * 1024 * 1024);
+         let response = Deno.core.dispatch(1, control);
+         // Async messages always have null response.
+         assert(response == null);
+         assert(asyncRecv == 0);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+      js_check(isolate.execute("check.js", "assert(asyncRecv == 1);"));
+    });
+  }
+
+  #[test]
+  fn overflow_res_async() {
+    run_in_task(|_cx| {
+      // TODO(ry) This test is quite slow due to memcpy-ing 100MB into JS. We
+      // should optimize this.
+      let (mut isolate, dispatch_count) = setup(Mode::OverflowResAsync);
+      js_check(isolate.execute(
+        "overflow_res_async.js",
+        r#"
+         let asyncRecv = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           assert(buf.byteLength === 100 * 1024 * 1024);
+           assert(buf[0] === 4);
+           asyncRecv++;
+         });
+         // Large message that will overflow the shared space.
+         let control = new Uint8Array([42]);
+         let response = Deno.core.dispatch(1, control);
+         assert(response == null);
+         assert(asyncRecv == 0);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      poll_until_ready(&mut isolate, 3).unwrap();
+      js_check(isolate.execute("check.js", "assert(asyncRecv == 1);"));
+    });
+  }
+
+  #[test]
+  fn overflow_res_multiple_dispatch_async() {
+    // TODO(ry) This test is quite slow due to memcpy-ing 100MB into JS. We
+    // should optimize this.
+    run_in_task(|_cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::OverflowResAsync);
+      js_check(isolate.execute(
+        "overflow_res_multiple_dispatch_async.js",
+        r#"
+         let asyncRecv = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           assert(buf.byteLength === 100 * 1024 * 1024);
+           assert(buf[0] === 4);
+           asyncRecv++;
+         });
+         // Large message that will overflow the shared space.
+         let control = new Uint8Array([42]);
+         let response = Deno.core.dispatch(1, control);
+         assert(response == null);
+         assert(asyncRecv == 0);
+         // Dispatch another message to verify that pending ops
+         // are done even if shared space overflows
+         Deno.core.dispatch(1, control);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
+      poll_until_ready(&mut isolate, 3).unwrap();
+      js_check(isolate.execute("check.js", "assert(asyncRecv == 2);"));
+    });
+  }
+
+  #[test]
+  fn test_pre_dispatch() {
+    run_in_task(|mut cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::OverflowResAsync);
+      js_check(isolate.execute(
+        "bad_op_id.js",
+        r#"
+          let thrown;
+          try {
+            Deno.core.dispatch(100, []);
+          } catch (e) {
+            thrown = e;
+          }
+          assert(String(thrown) === "TypeError: Unknown op id: 100");
+         "#,
+      ));
+      if let Poll::Ready(Err(_)) = isolate.poll_unpin(&mut cx) {
+        unreachable!();
+      }
+    });
+  }
+
+  #[test]
+  fn test_js() {
+    run_in_task(|mut cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      js_check(
+        isolate.execute(
+          "shared_queue_test.js",
+          include_str!("shared_queue_test.js"),
+        ),
+      );
+      if let Poll::Ready(Err(_)) = isolate.poll_unpin(&mut cx) {
+        unreachable!();
+      }
+    });
+  }
+
+  #[test]
+  fn startup_script_error() {
+    let startup_data = StartupData::Script(Script {
+      source: "throw new Error('startup failed')",
+      filename: "startup.js",
+    });
+    let mut isolate = Isolate::new(startup_data, false);
+    let err = isolate.execute("a.js", "1 + 1").unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.message, "Uncaught Error: startup failed");
+    // The startup script only runs once.
+    js_check(isolate.execute("b.js", "1 + 1"));
+  }
+
+  #[test]
+  fn js_error_name_message_stack() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute("a.js", "throw new TypeError('x')")
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.name, Some("TypeError".to_string()));
+    assert_eq!(js_error.exception_message, Some("x".to_string()));
+    assert!(js_error.stack.unwrap().starts_with("TypeError: x"));
+
+    let err = isolate.execute("b.js", "throw 42").unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.message, "Uncaught 42");
+    assert_eq!(js_error.name, None);
+    assert_eq!(js_error.exception_message, None);
+    assert_eq!(js_error.stack, None);
+    assert!(!js_error.is_terminated);
+  }
+
+  #[test]
+  fn js_error_cause() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute(
+        "a.js",
+        r#"
+          const inner = new TypeError("inner");
+          const outer = new Error("outer");
+          outer.cause = inner;
+          inner.cause = outer;
+          throw outer;
+        "#,
+      )
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.exception_message, Some("outer".to_string()));
+    let cause = js_error.cause.unwrap();
+    assert_eq!(cause.name, Some("TypeError".to_string()));
+    assert_eq!(cause.exception_message, Some("inner".to_string()));
+    // The cycle back to `outer` is not followed.
+    assert!(cause.cause.is_none());
+  }
+
+  #[test]
+  fn js_error_aggregate_errors() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute(
+        "a.js",
+        r#"
+          const err = new Error("all failed");
+          err.errors = [new TypeError("a"), new RangeError("b")];
+          throw err;
+        "#,
+      )
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    let names: Vec<_> =
+      js_error.errors.iter().map(|e| e.name.clone()).collect();
+    assert_eq!(
+      names,
+      vec![
+        Some("TypeError".to_string()),
+        Some("RangeError".to_string())
+      ]
+    );
+  }
+
+  #[test]
+  fn js_error_aggregate_errors_cyclic() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute(
+        "a.js",
+        r#"
+          const err = new Error("all failed");
+          const inner = new TypeError("inner");
+          inner.errors = [err, inner];
+          err.errors = [err, inner];
+          Object.defineProperty(err.errors, 2, {
+            get() { throw new Error("getter"); },
+          });
+          throw err;
+        "#,
+      )
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.errors.len(), 1);
+    assert_eq!(js_error.errors[0].name, Some("TypeError".to_string()));
+    assert!(js_error.errors[0].errors.is_empty());
+  }
+
+  #[test]
+  fn string_keys_cached() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    assert!(isolate.string_keys.is_empty());
+    isolate.execute("a.js", "throw new Error('a')").unwrap_err();
+    let len = isolate.string_keys.len();
+    assert!(isolate.string_keys.contains_key("stack"));
+    isolate.execute("b.js", "throw new Error('b')").unwrap_err();
+    assert_eq!(isolate.string_keys.len(), len);
+  }
+
+  #[test]
+  fn syntax_error() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let src = "hocuspocus(";
+    let r = isolate.execute("i.js", src);
+    let e = r.unwrap_err();
+    let js_error = e.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.end_column, Some(11));
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    run_in_task(|mut cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      js_check(isolate.execute(
+        "encode_decode_test.js",
+        include_str!("encode_decode_test.js"),
+      ));
+      if let Poll::Ready(Err(_)) = isolate.poll_unpin(&mut cx) {
+        unreachable!();
+      }
+    });
+  }
+
+  #[test]
+  fn will_snapshot() {
+    let snapshot = {
+      let mut isolate = Isolate::new(StartupData::None, true);
+      js_check(isolate.execute("a.js", "a = 1 + 2"));
+      isolate.snapshot().unwrap()
+    };
+
+    let startup_data = StartupData::OwnedSnapshot(snapshot);
+    let mut isolate2 = Isolate::new(startup_data, false);
+    js_check(isolate2.execute("check.js", "if (a != 3) throw Error('x')"));
+  }
+
+  fn answer(
+    scope: v8::FunctionCallbackScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+  ) {
+    rv.set(v8::Integer::new(scope, 42).into());
+  }
+
+  #[test]
+  fn snapshot_external_references() {
+    lazy_static! {
+      static ref EXTERNAL_REFERENCES: v8::ExternalReferences =
+        external_references(&[v8::ExternalReference {
+          function: answer.map_fn_to(),
+        }]);
+    }
+    let external_references = &*EXTERNAL_REFERENCES;
+
+    let snapshot = {
+      let mut isolate = IsolateBuilder::new()
+        .will_snapshot(true)
+        .external_references(external_references)
+        .build();
+      {
+        let v8_isolate = isolate.v8_isolate.as_mut().unwrap();
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = isolate.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut answer_tmpl = v8::FunctionTemplate::new(scope, answer);
+        let answer_val = answer_tmpl.get_function(scope, context).unwrap();
+        let key = v8::String::new(scope, "answer").unwrap();
+        context
+          .global(scope)
+          .set(context, key.into(), answer_val.into());
+      }
+      isolate.snapshot().unwrap()
+    };
+
+    let mut isolate = IsolateBuilder::new()
+      .startup_data(StartupData::OwnedSnapshot(snapshot))
+      .external_references(external_references)
+      .build();
+    js_check(
+      isolate.execute("check.js", "if (answer() !== 42) throw Error('x')"),
+    );
   }
 
-  let js_error = JSError::from_v8_exception(scope, exception);
-  let js_error = (js_error_create_fn)(js_error);
+  #[test]
+  fn abort_snapshot() {
+    let mut isolate = Isolate::new(StartupData::None, true);
+    js_check(isolate.execute("a.js", "a = 1 + 2"));
+    isolate.abort_snapshot();
+
+    // Aborting after a snapshot was taken is fine too.
+    let mut isolate = Isolate::new(StartupData::None, true);
+    let _snapshot = isolate.snapshot().unwrap();
+    isolate.abort_snapshot();
+  }
 
-  if is_terminating_exception {
-    // Re-enable exception termination.
-    // TODO(piscisaureus): in rusty_v8, `terminate_execution()` should also
-    // be implemented on `struct Isolate`.
-    scope.isolate().thread_safe_handle().terminate_execution();
+  #[test]
+  fn snapshot_pending_promise_rejection() {
+    let mut isolate = Isolate::new(StartupData::None, true);
+    js_check(isolate.execute("a.js", "Promise.reject(new Error('boom'))"));
+    let err = isolate.snapshot().unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.exception_message, Some("boom".to_string()));
+
+    // Once the rejection has been reported, the snapshot can be taken.
+    let _snapshot = isolate.snapshot().unwrap();
+    isolate.abort_snapshot();
   }
 
-  Err(js_error)
-}
+  #[test]
+  fn snapshot_warm_up() {
+    let snapshot = {
+      let mut isolate = Isolate::new(StartupData::None, true);
+      js_check(isolate.execute("a.js", "function add(a, b) { return a + b; }"));
+      js_check(isolate.snapshot_warm_up(
+        "warm_up.js",
+        r#"
+          for (let i = 0; i < 10; i++) add(i, i);
+          Deno.core.recv(() => {});
+          Deno.core.setMacrotaskCallback(() => true);
+          Deno.core.shared;
+        "#,
+      ));
+      isolate.snapshot().unwrap()
+    };
 
-fn check_promise_exceptions<'s>(
-  scope: &mut impl v8::ToLocal<'s>,
-  pending_promise_exceptions: &mut HashMap<i32, v8::Global<v8::Value>>,
-  js_error_create_fn: &JSErrorCreateFn,
-) -> Result<(), ErrBox> {
-  if let Some(&key) = pending_promise_exceptions.keys().next() {
-    let handle = pending_promise_exceptions.remove(&key).unwrap();
-    let exception = handle.get(scope).expect("empty error handle");
-    exception_to_err_result(scope, exception, js_error_create_fn)
-  } else {
-    Ok(())
+    let startup_data = StartupData::OwnedSnapshot(snapshot);
+    let mut isolate2 = Isolate::new(startup_data, false);
+    js_check(
+      isolate2.execute("check.js", "if (add(1, 2) != 3) throw Error('x')"),
+    );
   }
-}
 
-pub fn js_check<T>(r: Result<T, ErrBox>) -> T {
-  if let Err(e) = r {
-    panic!(e.to_string());
+  #[test]
+  fn explicit_microtasks_policy() {
+    let mut isolate = IsolateBuilder::new()
+      .microtasks_policy(v8::MicrotasksPolicy::Explicit)
+      .build();
+    js_check(isolate.execute(
+      "a.js",
+      r#"
+        globalThis.ran = false;
+        Deno.core.queueMicrotask(() => { globalThis.ran = true; });
+      "#,
+    ));
+    js_check(isolate.execute("b.js", "if (ran) throw Error('ran early')"));
+    isolate.run_microtasks();
+    js_check(isolate.execute("c.js", "if (!ran) throw Error('did not run')"));
+
+    isolate.set_microtasks_policy(v8::MicrotasksPolicy::Auto);
+    js_check(isolate.execute(
+      "d.js",
+      "ran = false; Deno.core.queueMicrotask(() => { ran = true; });",
+    ));
+    js_check(isolate.execute("e.js", "if (!ran) throw Error('did not run')"));
   }
-  r.unwrap()
-}
 
-#[cfg(test)]
-pub mod tests {
-  use super::*;
-  use futures::future::lazy;
-  use std::ops::FnOnce;
-  use std::sync::atomic::{AtomicUsize, Ordering};
+  #[test]
+  fn fallback_frame_without_call_sites() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute("throw.js", "let x = 1;\n  throw 42;")
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.frames.len(), 1);
+    let frame = &js_error.frames[0];
+    assert_eq!(frame.function_name.as_deref(), Some("<anonymous>"));
+    assert_eq!(frame.file_name.as_deref(), Some("throw.js"));
+    assert_eq!(frame.line_number, Some(2));
+    assert_eq!(frame.column_number, Some(3));
+  }
 
-  pub fn run_in_task<F>(f: F)
-  where
-    F: FnOnce(&mut Context) + Send + 'static,
-  {
-    futures::executor::block_on(lazy(move |cx| f(cx)));
+  #[test]
+  fn malformed_call_sites() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute(
+        "malformed.js",
+        r#"
+          const e = new Error("tampered");
+          e.__callSiteEvals = [
+            42,
+            { fileName: 1, isEval: "yes", columnNumber: 5, promiseIndex: 2 },
+          ];
+          eval("throw e");
+        "#,
+      )
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.frames.len(), 1);
+    assert_eq!(js_error.frames[0].file_name, None);
+    assert!(!js_error.frames[0].is_eval);
+    assert_eq!(js_error.frames[0].column_number, Some(5));
+    assert_eq!(js_error.frames[0].promise_index, Some(2));
+    assert!(js_error.formatted_frames.is_empty());
   }
 
-  fn poll_until_ready<F>(future: &mut F, max_poll_count: usize) -> F::Output
-  where
-    F: Future + Unpin,
-  {
-    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
-    for _ in 0..max_poll_count {
-      match future.poll_unpin(&mut cx) {
-        Poll::Pending => continue,
-        Poll::Ready(val) => return val,
-      }
+  #[test]
+  fn stack_trace_limit() {
+    fn count_frames(isolate: &mut Isolate) -> usize {
+      let err = isolate
+        .execute(
+          "deep.js",
+          r#"
+            function f(n) {
+              if (n === 0) throw new Error("deep");
+              f(n - 1);
+            }
+            f(20);
+          "#,
+        )
+        .unwrap_err();
+      let js_error = err.downcast::<JSError>().unwrap();
+      js_error.stack.unwrap().matches("\n    at ").count()
     }
-    panic!(
-      "Isolate still not ready after polling {} times.",
-      max_poll_count
-    )
+
+    let mut isolate = Isolate::new(StartupData::None, false);
+    assert_eq!(count_frames(&mut isolate), 10);
+
+    let mut isolate = IsolateBuilder::new().stack_trace_limit(15).build();
+    assert_eq!(count_frames(&mut isolate), 15);
+
+    js_check(isolate.set_stack_trace_limit(5));
+    assert_eq!(count_frames(&mut isolate), 5);
+
+    // Huge limits are clamped rather than wrapping around.
+    js_check(isolate.set_stack_trace_limit(usize::MAX));
+    js_check(isolate.execute(
+      "limit.js",
+      "if (Error.stackTraceLimit !== 2147483647) throw Error()",
+    ));
+
+    js_check(isolate.execute("replace.js", "globalThis.Error = 1"));
+    assert!(isolate.set_stack_trace_limit(5).is_err());
   }
 
-  pub enum Mode {
-    Async,
-    AsyncUnref,
-    OverflowReqSync,
-    OverflowResSync,
-    OverflowReqAsync,
-    OverflowResAsync,
+  #[test]
+  fn array_buffer_allocator() {
+    let mut isolate = IsolateBuilder::new()
+      .array_buffer_allocator(v8::new_default_allocator())
+      .build();
+    js_check(isolate.execute(
+      "array_buffer_allocator.js",
+      r#"
+        const buf = new Uint8Array(1024);
+        buf[1023] = 42;
+        if (buf[1023] !== 42) throw Error();
+      "#,
+    ));
   }
 
-  pub fn setup(mode: Mode) -> (Box<Isolate>, Arc<AtomicUsize>) {
-    let dispatch_count = Arc::new(AtomicUsize::new(0));
-    let dispatch_count_ = dispatch_count.clone();
+  #[test]
+  fn test_inspect() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute(
+      "inspect.js",
+      r#"
+        function assertInspect(value, expected, options) {
+          const actual = Deno.core.inspect(value, options);
+          if (actual !== expected) {
+            throw Error(`expected ${expected}, got ${actual}`);
+          }
+        }
+        assertInspect("str", "str");
+        assertInspect(42, "42");
+        assertInspect(undefined, "undefined");
+        assertInspect(Symbol("s"), "Symbol(s)");
+        assertInspect([1, "a", null], '[ 1, "a", null ]');
+        assertInspect({}, "{}");
+        assertInspect({ a: { b: { c: { d: 1 } } } }, "{ a: { b: { c: [Object] } } }");
+        assertInspect({ a: { b: 1 } }, "{ a: [Object] }", { depth: 0 });
+        assertInspect(new Map([["k", 1]]), 'Map { "k" => 1 }');
+        assertInspect(new Set([1, 2]), "Set { 1, 2 }");
+        assertInspect(function foo() {}, "[Function: foo]");
+        const circular = { name: "c" };
+        circular.self = circular;
+        assertInspect(circular, '{ name: "c", self: [Circular] }');
+        // Repeated, non-circular references are not cycles.
+        const shared = { x: 1 };
+        assertInspect([shared, shared], "[ { x: 1 }, { x: 1 } ]");
+        // A throwing getter propagates instead of aborting the process.
+        const throwing = { get a() { throw new Error("getter"); } };
+        let caught;
+        try {
+          Deno.core.inspect(throwing);
+        } catch (e) {
+          caught = e;
+        }
+        if (!caught || caught.message !== "getter") {
+          throw Error("getter not rethrown");
+        }
+      "#,
+    ));
+  }
 
+  #[test]
+  fn test_now() {
     let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute(
+      "now.js",
+      r#"
+        let prev = Deno.core.now();
+        if (typeof prev !== "number" || prev < 0) throw Error("bad now");
+        // Sub-millisecond resolution: sooner or later a sample isn't a whole
+        // number of milliseconds.
+        let t = prev;
+        while (Number.isInteger(t)) {
+          t = Deno.core.now();
+          if (t < prev) throw Error("not monotonic");
+          prev = t;
+        }
+      "#,
+    ));
+  }
 
-    let dispatcher =
-      move |control: &[u8], _zero_copy: Option<ZeroCopyBuf>| -> Op {
-        dispatch_count_.fetch_add(1, Ordering::Relaxed);
-        match mode {
-          Mode::Async => {
-            assert_eq!(control.len(), 1);
-            assert_eq!(control[0], 42);
-            let buf = vec![43u8].into_boxed_slice();
-            Op::Async(futures::future::ready(buf).boxed())
-          }
-          Mode::AsyncUnref => {
-            assert_eq!(control.len(), 1);
-            assert_eq!(control[0], 42);
-            let fut = async {
-              // This future never finish.
-              futures::future::pending::<()>().await;
-              vec![43u8].into_boxed_slice()
-            };
-            Op::AsyncUnref(fut.boxed())
-          }
-          Mode::OverflowReqSync => {
-            assert_eq!(control.len(), 100 * 1024 * 1024);
-            let buf = vec![43u8].into_boxed_slice();
-            Op::Sync(buf)
-          }
-          Mode::OverflowResSync => {
-            assert_eq!(control.len(), 1);
-            assert_eq!(control[0], 42);
-            let mut vec = Vec::<u8>::new();
-            vec.resize(100 * 1024 * 1024, 0);
-            vec[0] = 99;
-            let buf = vec.into_boxed_slice();
-            Op::Sync(buf)
-          }
-          Mode::OverflowReqAsync => {
-            assert_eq!(control.len(), 100 * 1024 * 1024);
-            let buf = vec![43u8].into_boxed_slice();
-            Op::Async(futures::future::ready(buf).boxed())
-          }
-          Mode::OverflowResAsync => {
-            assert_eq!(control.len(), 1);
-            assert_eq!(control[0], 42);
-            let mut vec = Vec::<u8>::new();
-            vec.resize(100 * 1024 * 1024, 0);
-            vec[0] = 4;
-            let buf = vec.into_boxed_slice();
-            Op::Async(futures::future::ready(buf).boxed())
+  #[test]
+  fn set_shared_queue_size() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::Async);
+      js_check(isolate.execute(
+        "setup2.js",
+        r#"
+         globalThis.oldShared = Deno.core.shared;
+         let nrecv = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           nrecv++;
+         });
+         "#,
+      ));
+      isolate.set_shared_queue_size(RECOMMENDED_SIZE * 2).unwrap();
+      js_check(isolate.execute(
+        "check1.js",
+        r#"
+         assert(Deno.core.shared !== oldShared);
+         assert(Deno.core.shared.byteLength > oldShared.byteLength);
+         let control = new Uint8Array([42]);
+         Deno.core.send(1, control);
+         assert(nrecv == 0);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+      js_check(isolate.execute("check2.js", "assert(nrecv == 1)"));
+
+      // Undelivered responses would be lost, so the queue isn't replaced.
+      assert!(isolate.shared.push(1, &[1, 2, 3]));
+      let err = isolate.set_shared_queue_size(RECOMMENDED_SIZE).unwrap_err();
+      assert!(err.downcast_ref::<SharedQueueNotEmpty>().is_some());
+      assert_eq!(isolate.shared.size(), 1);
+    });
+  }
+
+  #[test]
+  fn send_detached_buffer() {
+    let (mut isolate, dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "buffers.js",
+      r#"
+        globalThis.control = new Uint8Array([42]);
+        globalThis.zeroCopy = new Uint8Array([1, 2, 3]);
+      "#,
+    ));
+    {
+      let v8_isolate = isolate.v8_isolate.as_mut().unwrap();
+      let mut hs = v8::HandleScope::new(v8_isolate);
+      let scope = hs.enter();
+      let context = isolate.global_context.get(scope).unwrap();
+      let mut cs = v8::ContextScope::new(scope, context);
+      let scope = cs.enter();
+      for name in &["control", "zeroCopy"] {
+        let key = v8::String::new(scope, name).unwrap();
+        let view: v8::Local<v8::ArrayBufferView> = context
+          .global(scope)
+          .get(scope, context, key.into())
+          .unwrap()
+          .try_into()
+          .unwrap();
+        view.buffer().unwrap().detach();
+      }
+    }
+    js_check(isolate.execute(
+      "detached.js",
+      r#"
+        function assertDetachedError(fn) {
+          try {
+            fn();
+          } catch (e) {
+            assert(e instanceof TypeError);
+            assert(e.message === "Cannot send detached ArrayBuffer");
+            return;
           }
+          throw Error("expected TypeError");
         }
-      };
+        assertDetachedError(() => Deno.core.send(1, control));
+        assertDetachedError(
+          () => Deno.core.send(1, new Uint8Array([42]), zeroCopy)
+        );
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 0);
+  }
 
-    isolate.register_op("test", dispatcher);
+  #[test]
+  fn execute_module() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute_module(
+      "module.js",
+      r#"
+        if (this !== undefined) throw Error("not strict");
+        if (typeof import.meta !== "object") throw Error("no import.meta");
+        globalThis.value = await Promise.resolve(42);
+      "#,
+    ));
+    js_check(isolate.execute("check.js", "if (value !== 42) throw Error()"));
+
+    let err = isolate
+      .execute_module("throws.js", "throw new Error('boom');")
+      .unwrap_err();
+    assert!(err.to_string().contains("boom"));
 
+    let err = isolate
+      .execute_module("imports.js", "import './other.js';")
+      .unwrap_err();
+    assert!(err.to_string().contains("./other.js"));
+  }
+
+  #[test]
+  fn test_eval_to_json() {
+    let mut isolate = Isolate::new(StartupData::None, false);
     js_check(isolate.execute(
-      "setup.js",
+      "eval_to_json.js",
       r#"
         function assert(cond) {
           if (!cond) {
             throw Error("assert");
           }
         }
-        "#,
+        let [json, err] = Deno.core.evalToJson("({ a: [1, 'b'], c: null })");
+        assert(json === '{"a":[1,"b"],"c":null}');
+        assert(err === null);
+
+        [json, err] = Deno.core.evalToJson("undefined");
+        assert(json === null);
+        assert(err === null);
+
+        [json, err] = Deno.core.evalToJson("(function f() {})");
+        assert(json === null);
+        assert(err === null);
+
+        [json, err] = Deno.core.evalToJson("throw new TypeError('x')");
+        assert(json === null);
+        assert(err.thrown instanceof TypeError);
+        assert(err.isNativeError && !err.isCompileError);
+
+        [json, err] = Deno.core.evalToJson("(");
+        assert(json === null);
+        assert(err.isCompileError);
+
+        [json, err] = Deno.core.evalToJson("const o = {}; o.o = o; o");
+        assert(json === null);
+        assert(err.thrown instanceof TypeError);
+      "#,
     ));
-    assert_eq!(dispatch_count.load(Ordering::Relaxed), 0);
-    (isolate, dispatch_count)
   }
 
   #[test]
-  fn test_dispatch() {
-    let (mut isolate, dispatch_count) = setup(Mode::Async);
+  fn expose_gc() {
+    let mut isolate = Isolate::new(StartupData::None, false);
     js_check(isolate.execute(
-      "filename.js",
+      "no_gc.js",
+      "if (typeof Deno.core.gc !== 'undefined') throw Error()",
+    ));
+    isolate.low_memory_notification();
+
+    let mut isolate = IsolateBuilder::new().expose_gc(true).build();
+    js_check(isolate.execute(
+      "gc.js",
+      r#"
+        let garbage = new Array(1e5).fill({});
+        garbage = null;
+        Deno.core.gc();
+      "#,
+    ));
+  }
+
+  #[test]
+  fn test_timers() {
+    run_in_task(|cx| {
+      let mut isolate = Isolate::new(StartupData::None, false);
+      let delays = Arc::new(Mutex::new(vec![]));
+      let delays_ = delays.clone();
+      isolate.register_timer_op(move |control, _| {
+        let (timer_id, delay) = control.split_at(4);
+        delays_
+          .lock()
+          .unwrap()
+          .push(u32::from_le_bytes(delay.try_into().unwrap()));
+        // Resolve right away, the deadline still decides the order.
+        let buf = timer_id.to_vec().into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      });
+      js_check(isolate.execute(
+        "timers.js",
+        r#"
+         const fired = [];
+         setTimeout(() => fired.push("b"), 20);
+         setTimeout(() => fired.push("a"), 10);
+         const id = setTimeout(() => fired.push("cleared"), 0);
+         setTimeout(() => fired.push("c"), 20);
+         clearTimeout(id);
+         "#,
+      ));
+      assert_eq!(*delays.lock().unwrap(), vec![20, 10, 0, 20]);
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+      js_check(isolate.execute(
+        "check.js",
+        r#"
+         if (fired.join() !== "a,b,c") throw Error(fired.join());
+         "#,
+      ));
+    })
+  }
+
+  #[test]
+  fn test_timers_without_op() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute(
+      "timers.js",
       r#"
-        let control = new Uint8Array([42]);
-        Deno.core.send(1, control);
-        async function main() {
-          Deno.core.send(1, control);
-        }
-        main();
-        "#,
+       if (typeof setTimeout !== "undefined") throw Error("setTimeout");
+       if (typeof clearTimeout !== "undefined") throw Error("clearTimeout");
+       "#,
     ));
-    assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
   }
 
   #[test]
-  fn test_poll_async_delayed_ops() {
+  fn test_timers_throwing_callback() {
     run_in_task(|cx| {
-      let (mut isolate, dispatch_count) = setup(Mode::Async);
-
-      js_check(isolate.execute(
-        "setup2.js",
-        r#"
-         let nrecv = 0;
-         Deno.core.setAsyncHandler(1, (buf) => {
-           nrecv++;
-         });
-         "#,
-      ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 0);
+      let mut isolate = Isolate::new(StartupData::None, false);
+      isolate.register_timer_op(|control, _| {
+        let buf = control[..4].to_vec().into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      });
       js_check(isolate.execute(
-        "check1.js",
+        "timers.js",
         r#"
-         assert(nrecv == 0);
-         let control = new Uint8Array([42]);
-         Deno.core.send(1, control);
-         assert(nrecv == 0);
+         const fired = [];
+         setTimeout(() => { throw Error("first"); }, 10);
+         setTimeout(() => fired.push("second"), 20);
          "#,
       ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Err(_)) => true,
+        _ => false,
+      });
+      // The timer that didn't get to run fires on the next poll.
       assert!(match isolate.poll_unpin(cx) {
         Poll::Ready(Ok(_)) => true,
         _ => false,
       });
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
       js_check(isolate.execute(
-        "check2.js",
+        "check.js",
         r#"
-         assert(nrecv == 1);
-         Deno.core.send(1, control);
-         assert(nrecv == 1);
+         if (fired.join() !== "second") throw Error(fired.join());
          "#,
       ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
+    })
+  }
+
+  #[test]
+  fn test_timers_bad_response() {
+    run_in_task(|cx| {
+      let mut isolate = Isolate::new(StartupData::None, false);
+      isolate.register_timer_op(|_, _| {
+        let buf = vec![1u8].into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      });
+      js_check(isolate.execute("timers.js", "setTimeout(() => {}, 0)"));
       assert!(match isolate.poll_unpin(cx) {
-        Poll::Ready(Ok(_)) => true,
+        Poll::Ready(Err(_)) => true,
         _ => false,
       });
-      js_check(isolate.execute("check3.js", "assert(nrecv == 2)"));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
-      // We are idle, so the next poll should be the last.
+    })
+  }
+
+  #[test]
+  fn test_timers_sync_op() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    isolate.register_timer_op(|_, _| Op::Sync(Box::new([])));
+    let err = isolate
+      .execute("timers.js", "setTimeout(() => {}, 0)")
+      .unwrap_err();
+    assert!(err.to_string().contains("The timer op must be async"));
+    assert!(isolate.pending_timers.is_empty());
+  }
+
+  #[test]
+  fn test_timers_snapshot() {
+    let mut isolate = Isolate::new(StartupData::None, true);
+    isolate
+      .register_timer_op(|_, _| Op::Async(futures::future::pending().boxed()));
+    js_check(isolate.execute("timers.js", "setTimeout(() => {}, 1000)"));
+    let _snapshot = isolate.snapshot().unwrap();
+    assert!(isolate.pending_timers.is_empty());
+    isolate.abort_snapshot();
+  }
+
+  #[test]
+  fn test_get_global() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute(
+      "globals.js",
+      r#"
+        globalThis.config = { port: 4545, hosts: ["a", "b"] };
+        globalThis.big = 1n;
+        globalThis.func = function() {};
+        globalThis.sym = Symbol("s");
+        Object.defineProperty(globalThis, "throws", {
+          get() { throw Error("nope"); },
+        });
+      "#,
+    ));
+    assert_eq!(
+      isolate.get_global_json("config").unwrap(),
+      r#"{"port":4545,"hosts":["a","b"]}"#
+    );
+    assert!(isolate.get_global("config").is_some());
+    assert!(isolate.get_global("missing").is_none());
+    assert!(isolate.get_global_json("missing").is_none());
+    // BigInts can't be serialized to JSON.
+    assert!(isolate.get_global("big").is_some());
+    assert!(isolate.get_global_json("big").is_none());
+    assert!(isolate.get_global("func").is_some());
+    assert!(isolate.get_global_json("func").is_none());
+    assert!(isolate.get_global_json("sym").is_none());
+    assert!(isolate.get_global("throws").is_none());
+    // Nothing was left pending by the failed reads.
+    js_check(isolate.execute("check.js", "1"));
+  }
+
+  #[test]
+  fn test_set_global() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    isolate
+      .set_global("config", r#"{"port":4545,"hosts":["a","b"]}"#)
+      .unwrap();
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+        if (config.port !== 4545 || config.hosts[1] !== "b") {
+          throw Error("bad config");
+        }
+      "#,
+    ));
+    isolate.set_global("config", "null").unwrap();
+    js_check(isolate.execute(
+      "check2.js",
+      "if (config !== null) throw Error('not overwritten')",
+    ));
+
+    let err = isolate.set_global("bad", "{").unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert!(js_error.message.starts_with("Uncaught SyntaxError"));
+
+    js_check(isolate.execute("freeze.js", "Object.freeze(globalThis)"));
+    let err = isolate.set_global("config", "1").unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert!(js_error.message.starts_with("Uncaught TypeError"));
+  }
+
+  #[test]
+  fn test_execute_result() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    assert_eq!(
+      isolate
+        .execute_result_json("a.js", "const a = { b: [1, 2] }; a.b.push(3); a")
+        .unwrap(),
+      Some(r#"{"b":[1,2,3]}"#.to_string())
+    );
+    assert_eq!(
+      isolate.execute_result_json("b.js", "1 + 1").unwrap(),
+      Some("2".to_string())
+    );
+    assert_eq!(
+      isolate.execute_result_json("c.js", "undefined").unwrap(),
+      None
+    );
+    assert_eq!(
+      isolate
+        .execute_result_json("d.js", "(function f() {})")
+        .unwrap(),
+      None
+    );
+    assert_eq!(
+      isolate.execute_result_json("d.js", "Symbol('s')").unwrap(),
+      None
+    );
+    assert!(isolate.execute_result("e.js", "throw Error('x')").is_err());
+    assert!(isolate
+      .execute_result_json("f.js", "throw Error('x')")
+      .is_err());
+  }
+
+  #[test]
+  fn test_respond_zero_copy() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "setup2.js",
+      r#"
+        let response;
+        Deno.core.setAsyncHandler(1, (buf) => {
+          response = buf;
+        });
+      "#,
+    ));
+    let backing_store = v8::ArrayBuffer::new_backing_store_from_boxed_slice(
+      vec![1, 2, 3].into_boxed_slice(),
+    )
+    .make_shared();
+    isolate.respond_zero_copy(1, backing_store.clone()).unwrap();
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+        assert(response instanceof Uint8Array);
+        assert(response.byteLength === 3);
+        assert(response[0] === 1 && response[2] === 3);
+        // The view shares memory with the host.
+        response[1] = 42;
+      "#,
+    ));
+    let bytes = unsafe { &**backing_store.get() };
+    assert_eq!(bytes, &[1, 42, 3][..]);
+
+    // Without a recv callback there's nobody to deliver the response to.
+    let mut isolate = Isolate::new(StartupData::None, false);
+    assert!(isolate.respond_zero_copy(1, backing_store).is_err());
+  }
+
+  #[test]
+  fn test_op_stats() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      js_check(isolate.execute(
+        "op_stats.js",
+        r#"
+         Deno.core.setAsyncHandler(1, () => {});
+         Deno.core.send(1, new Uint8Array([42]));
+         Deno.core.send(1, new Uint8Array([42]), new Uint8Array(10));
+         "#,
+      ));
       assert!(match isolate.poll_unpin(cx) {
         Poll::Ready(Ok(_)) => true,
         _ => false,
       });
-    });
+      assert_eq!(
+        isolate.op_stats(),
+        vec![OpStat {
+          op_id: 1,
+          calls: 2,
+          bytes_received: 12,
+          bytes_sent: 2,
+        }]
+      );
+    })
   }
 
   #[test]
-  fn test_poll_async_optional_ops() {
+  fn test_detach_array_buffer() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "detach.js",
+      r#"
+        globalThis.view = new Uint8Array([1, 2, 3]);
+        globalThis.buffer = view.buffer;
+        globalThis.notABuffer = {};
+      "#,
+    ));
+    let not_a_buffer = isolate.get_global("notABuffer").unwrap();
+    assert!(isolate.detach_array_buffer(not_a_buffer).is_none());
+
+    let buffer = isolate.get_global("buffer").unwrap();
+    let backing_store = isolate.detach_array_buffer(buffer).unwrap();
+    let bytes = unsafe { &**backing_store.get() };
+    assert_eq!(bytes, &[1, 2, 3][..]);
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+        assert(buffer.byteLength === 0);
+        let thrown;
+        try {
+          Deno.core.send(1, new Uint8Array([42]), view);
+        } catch (e) {
+          thrown = e;
+        }
+        assert(thrown.message === "Cannot send detached ArrayBuffer");
+      "#,
+    ));
+
+    // The memory can be handed to another isolate.
+    let (mut isolate2, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate2.execute(
+      "setup2.js",
+      r#"
+        let received;
+        Deno.core.setAsyncHandler(1, (buf) => {
+          received = buf;
+        });
+      "#,
+    ));
+    isolate2.respond_zero_copy(1, backing_store).unwrap();
+    js_check(isolate2.execute(
+      "check2.js",
+      "assert(received.length === 3 && received[2] === 3);",
+    ));
+  }
+
+  #[test]
+  fn test_realms() {
+    let (mut isolate, dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute("main.js", "globalThis.x = 'main';"));
+
+    let realm = isolate.create_realm();
+    assert_ne!(realm, MAIN_REALM);
+    js_check(isolate.execute_in_realm(
+      realm,
+      "realm.js",
+      r#"
+        if (typeof x !== "undefined") throw Error("realm sees main globals");
+        globalThis.x = "realm";
+        globalThis.received = null;
+        Deno.core.recv((opId, buf) => {
+          received = buf;
+        });
+        Deno.core.send(1, new Uint8Array([42]));
+      "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+    js_check(isolate.execute("check.js", "assert(x === 'main');"));
+
+    js_check(isolate.respond_in_realm(realm, 1, Box::new([7])));
+    js_check(isolate.execute_in_realm(
+      realm,
+      "check_realm.js",
+      r#"
+        if (x !== "realm") throw Error("main realm leaked into realm");
+        if (received[0] !== 7) throw Error("response not received");
+      "#,
+    ));
+
+    let err = isolate
+      .execute_in_realm(realm + 1, "unknown.js", "")
+      .unwrap_err();
+    assert!(err.downcast_ref::<UnknownRealm>().is_some());
+    let err = isolate
+      .respond_in_realm(realm + 1, 1, Box::new([7]))
+      .unwrap_err();
+    assert!(err.downcast_ref::<UnknownRealm>().is_some());
+  }
+
+  #[test]
+  fn test_realm_async_responses() {
     run_in_task(|cx| {
-      let (mut isolate, dispatch_count) = setup(Mode::AsyncUnref);
+      let (mut isolate, dispatch_count) = setup(Mode::Async);
       js_check(isolate.execute(
-        "check1.js",
+        "main.js",
         r#"
+          let nrecv = 0;
           Deno.core.setAsyncHandler(1, (buf) => {
-            // This handler will never be called
-            assert(false);
+            nrecv++;
           });
-          let control = new Uint8Array([42]);
-          Deno.core.send(1, control);
         "#,
       ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
-      // The above op never finish, but isolate can finish
-      // because the op is an unreffed async op.
+      let realm = isolate.create_realm();
+      js_check(isolate.execute_in_realm(
+        realm,
+        "realm.js",
+        r#"
+          globalThis.received = [];
+          Deno.core.setAsyncHandler(1, (buf) => {
+            received.push(buf[0]);
+          });
+          Deno.core.send(1, new Uint8Array([42]));
+          Deno.core.send(1, new Uint8Array([42]));
+        "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
       assert!(match isolate.poll_unpin(cx) {
         Poll::Ready(Ok(_)) => true,
         _ => false,
       });
+      // The responses went through the realm's own shared queue.
+      js_check(isolate.execute_in_realm(
+        realm,
+        "check_realm.js",
+        r#"
+          if (received.join() !== "43,43") throw Error(received.join());
+          if (Deno.core.shared.byteLength === 0) throw Error("no shared queue");
+        "#,
+      ));
+      js_check(isolate.execute("check.js", "assert(nrecv === 0);"));
     })
   }
 
   #[test]
-  fn terminate_execution() {
-    let (mut isolate, _dispatch_count) = setup(Mode::Async);
-    // TODO(piscisaureus): in rusty_v8, the `thread_safe_handle()` method
-    // should not require a mutable reference to `struct rusty_v8::Isolate`.
-    let v8_isolate_handle =
-      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+  fn test_realm_fast_channel() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    isolate.register_op("sum", |control, _| {
+      let sum: u32 = control.iter().map(|b| u32::from(*b)).sum();
+      Op::Sync(Box::new(sum.to_le_bytes()))
+    });
+    let realm = isolate.create_realm();
+    isolate.setup_fast_channel(16);
+    isolate.setup_fast_channel_in_realm(realm, 8).unwrap();
+    js_check(isolate.execute_in_realm(
+      realm,
+      "realm.js",
+      r#"
+        const channel = Deno.core.fastChannel();
+        if (channel.length !== 8) throw Error("got the main realm's channel");
+        channel.set([1, 2, 3]);
+        const res = Deno.core.dispatchFast(Deno.core.ops().sum, 3);
+        if (res[0] !== 6) throw Error("bad response");
+      "#,
+    ));
+    js_check(isolate.execute(
+      "main.js",
+      r#"
+        const channel = Deno.core.fastChannel();
+        if (channel.length !== 16) throw Error("got the realm's channel");
+        if (channel[0] !== 0) throw Error("realm wrote to the main channel");
+      "#,
+    ));
 
-    let terminator_thread = std::thread::spawn(move || {
-      // allow deno to boot and run
-      std::thread::sleep(std::time::Duration::from_millis(100));
+    let err = isolate
+      .setup_fast_channel_in_realm(realm + 1, 8)
+      .unwrap_err();
+    assert!(err.downcast_ref::<UnknownRealm>().is_some());
+  }
 
-      // terminate execution
-      let ok = v8_isolate_handle.terminate_execution();
-      assert!(ok);
+  #[test]
+  fn test_dispatch_console() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let result =
+      isolate.execute("no_op.js", "Deno.core.dispatchConsole('log', []);");
+    assert!(result.is_err());
+
+    let calls = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let calls_ = calls.clone();
+    isolate.register_console_op(move |control, _| {
+      calls_.borrow_mut().push(control.to_vec());
+      Op::Sync(Box::new([]))
     });
+    js_check(isolate.execute(
+      "console.js",
+      r#"
+        Deno.core.dispatchConsole("count", ["label", 2]);
+      "#,
+    ));
 
-    // Rn an infinite loop, which should be terminated.
-    match isolate.execute("infinite_loop.js", "for(;;) {}") {
-      Ok(_) => panic!("execution should be terminated"),
-      Err(e) => {
-        assert_eq!(e.to_string(), "Uncaught Error: execution terminated")
-      }
-    };
+    let control = calls.borrow_mut().pop().unwrap();
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&control[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    assert_eq!(&control[4..4 + len], b"count");
+
+    let args: serde_json::Value =
+      serde_json::from_slice(&control[4 + len..]).unwrap();
+    assert_eq!(args, serde_json::json!(["label", 2]));
+
+    let result = isolate.execute(
+      "cyclic.js",
+      "const a = []; a.push(a); Deno.core.dispatchConsole('log', a);",
+    );
+    assert!(result.is_err());
+    assert!(calls.borrow().is_empty());
+  }
 
-    // Cancel the execution-terminating exception in order to allow script
-    // execution again.
-    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
-    // also be implemented on `struct Isolate`.
-    let ok = isolate
-      .v8_isolate
-      .as_mut()
-      .unwrap()
-      .thread_safe_handle()
-      .cancel_terminate_execution();
-    assert!(ok);
+  #[test]
+  fn test_execute_with_offset() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute_with_offset("wrapped.js", "\n  throw new Error('x');", 10, 4)
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.script_resource_name.as_deref(), Some("wrapped.js"));
+    assert_eq!(js_error.line_number, Some(12));
+  }
 
-    // Verify that the isolate usable again.
+  #[test]
+  fn test_sync_i32_op() {
+    let mut isolate = Isolate::new(StartupData::None, false);
     isolate
-      .execute("simple.js", "1 + 1")
-      .expect("execution should be possible again");
+      .register_op("count", |control, _| Op::SyncI32(control.len() as i32));
+    js_check(isolate.execute(
+      "sync_i32.js",
+      r#"
+        const opId = Deno.core.ops().count;
+        const length = Deno.core.send(opId, new Uint8Array(3));
+        if (length !== 3) throw Error("expected 3, got " + length);
+        const empty = Deno.core.send(opId);
+        if (empty !== 0) throw Error("expected 0, got " + empty);
+      "#,
+    ));
+  }
 
-    terminator_thread.join().unwrap();
+  #[test]
+  fn test_abort_op() {
+    run_in_task(|cx| {
+      let mut isolate = Isolate::new(StartupData::None, false);
+      let hang_id = isolate.register_op("hang", |_, _| {
+        Op::Async(futures::future::pending::<Buf>().boxed())
+      });
+      js_check(isolate.execute(
+        "abort_op.js",
+        r#"
+          const { hang } = Deno.core.ops();
+          Deno.core.setAsyncHandler(hang, () => {
+            throw Error("unexpected response");
+          });
+          Deno.core.setAbortHandler(hang, (e) => {
+            globalThis.abortMessage = e.message;
+          });
+          Deno.core.dispatch(hang);
+        "#,
+      ));
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Pending => true,
+        _ => false,
+      });
+
+      js_check(isolate.abort_op(hang_id, "hang was aborted"));
+      js_check(isolate.execute(
+        "check1.js",
+        r#"
+          if (abortMessage !== "hang was aborted") {
+            throw Error("unexpected message " + abortMessage);
+          }
+        "#,
+      ));
+      // The aborted op no longer keeps the isolate busy.
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+
+      // Without an abort handler, the error is returned to the host.
+      let never_id = isolate.register_op("never", |_, _| {
+        Op::Async(futures::future::pending::<Buf>().boxed())
+      });
+      js_check(
+        isolate
+          .execute("never.js", "Deno.core.dispatch(Deno.core.ops().never)"),
+      );
+      let err = isolate.abort_op(never_id, "never was aborted").unwrap_err();
+      let js_error = err.downcast::<JSError>().unwrap();
+      assert_eq!(js_error.message, "Uncaught Error: never was aborted");
+      assert!(match isolate.poll_unpin(cx) {
+        Poll::Ready(Ok(_)) => true,
+        _ => false,
+      });
+
+      // The error goes to the realm that dispatched the op.
+      let realm = isolate.create_realm();
+      js_check(isolate.execute_in_realm(
+        realm,
+        "realm_abort.js",
+        &format!(
+          r#"
+            globalThis.abortMessage = null;
+            Deno.core.recv((opId, buf, e) => {{ abortMessage = e.message; }});
+            Deno.core.send({});
+          "#,
+          hang_id
+        ),
+      ));
+      js_check(isolate.abort_op(hang_id, "realm hang was aborted"));
+      js_check(isolate.execute_in_realm(
+        realm,
+        "check_realm.js",
+        r#"
+          if (abortMessage !== "realm hang was aborted") {
+            throw Error("unexpected message " + abortMessage);
+          }
+        "#,
+      ));
+      js_check(isolate.execute(
+        "check2.js",
+        r#"
+          if (abortMessage !== "hang was aborted") {
+            throw Error("main realm got " + abortMessage);
+          }
+        "#,
+      ));
+
+      // A realm without a recv callback gets an error, not a panic.
+      let realm = isolate.create_realm();
+      js_check(isolate.execute_in_realm(
+        realm,
+        "realm_no_recv.js",
+        &format!("Deno.core.send({});", hang_id),
+      ));
+      let err = isolate.abort_op(hang_id, "no recv").unwrap_err();
+      let js_error = err.downcast::<JSError>().unwrap();
+      assert!(js_error
+        .message
+        .contains("Deno.core.recv has not been called."));
+    })
   }
 
   #[test]
-  fn dangling_shared_isolate() {
-    let v8_isolate_handle = {
-      // isolate is dropped at the end of this block
-      let (mut isolate, _dispatch_count) = setup(Mode::Async);
-      // TODO(piscisaureus): in rusty_v8, the `thread_safe_handle()` method
-      // should not require a mutable reference to `struct rusty_v8::Isolate`.
-      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle()
-    };
+  fn test_fast_channel() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    isolate.register_op("sum", |control, _| {
+      let sum: u32 = control.iter().map(|b| u32::from(*b)).sum();
+      Op::Sync(Box::new(sum.to_le_bytes()))
+    });
+    isolate.register_op("sum_async", |_, _| {
+      Op::Async(futures::future::ready(Box::new([]) as Buf).boxed())
+    });
 
-    // this should not SEGFAULT
-    v8_isolate_handle.terminate_execution();
-  }
+    js_check(isolate.execute(
+      "no_channel.js",
+      r#"
+        let thrown;
+        try {
+          Deno.core.dispatchFast(Deno.core.ops().sum, 0);
+        } catch (e) {
+          thrown = e;
+        }
+        if (Deno.core.fastChannel() !== null) throw Error("unexpected channel");
+        if (!thrown) throw Error("expected dispatchFast to throw");
+      "#,
+    ));
 
-  #[test]
-  fn overflow_req_sync() {
-    let (mut isolate, dispatch_count) = setup(Mode::OverflowReqSync);
+    isolate.setup_fast_channel(16);
     js_check(isolate.execute(
-      "overflow_req_sync.js",
+      "fast_channel.js",
       r#"
-        let asyncRecv = 0;
-        Deno.core.setAsyncHandler(1, (buf) => { asyncRecv++ });
-        // Large message that will overflow the shared space.
-        let control = new Uint8Array(100 * 1024 * 1024);
-        let response = Deno.core.dispatch(1, control);
-        assert(response instanceof Uint8Array);
-        assert(response.length == 1);
-        assert(response[0] == 43);
-        assert(asyncRecv == 0);
-        "#,
+        const channel = Deno.core.fastChannel();
+        if (channel.length !== 16) throw Error("bad channel size");
+        channel.set([1, 2, 3]);
+        const res = Deno.core.dispatchFast(Deno.core.ops().sum, 3);
+        if (res.length !== 4 || res[0] !== 6) throw Error("bad response");
+        if (res.buffer !== channel.buffer) throw Error("response was copied");
+        const asyncRes = Deno.core.dispatchFast(Deno.core.ops().sum_async, 0);
+        if (asyncRes !== null) throw Error("async op responded in place");
+      "#,
     ));
-    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
   }
 
   #[test]
-  fn overflow_res_sync() {
-    // TODO(ry) This test is quite slow due to memcpy-ing 100MB into JS. We
-    // should optimize this.
-    let (mut isolate, dispatch_count) = setup(Mode::OverflowResSync);
+  fn test_json_parse_stringify() {
+    let mut isolate = Isolate::new(StartupData::None, false);
     js_check(isolate.execute(
-      "overflow_res_sync.js",
+      "json.js",
       r#"
-        let asyncRecv = 0;
-        Deno.core.setAsyncHandler(1, (buf) => { asyncRecv++ });
-        // Large message that will overflow the shared space.
-        let control = new Uint8Array([42]);
-        let response = Deno.core.dispatch(1, control);
-        assert(response instanceof Uint8Array);
-        assert(response.length == 100 * 1024 * 1024);
-        assert(response[0] == 99);
-        assert(asyncRecv == 0);
-        "#,
+        // Tampering with the global JSON must not affect Deno.core.
+        JSON.parse = JSON.stringify = () => { throw Error("tampered"); };
+
+        const value = Deno.core.jsonParse('{"a":[1,"two",null]}');
+        if (value.a[1] !== "two") throw Error("bad parse");
+        const json = Deno.core.jsonStringify(value);
+        if (json !== '{"a":[1,"two",null]}') throw Error("bad stringify");
+        if (Deno.core.jsonStringify(undefined) !== undefined) {
+          throw Error("undefined has no JSON representation");
+        }
+
+        let thrown;
+        try {
+          Deno.core.jsonParse("{");
+        } catch (e) {
+          thrown = e;
+        }
+        if (!(thrown instanceof SyntaxError)) throw Error("expected SyntaxError");
+
+        const cyclic = {};
+        cyclic.self = cyclic;
+        thrown = undefined;
+        try {
+          Deno.core.jsonStringify(cyclic);
+        } catch (e) {
+          thrown = e;
+        }
+        if (!(thrown instanceof TypeError)) throw Error("expected TypeError");
+      "#,
     ));
-    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
   }
 
   #[test]
-  fn overflow_req_async() {
-    run_in_task(|cx| {
-      let (mut isolate, dispatch_count) = setup(Mode::OverflowReqAsync);
-      js_check(isolate.execute(
-        "overflow_req_async.js",
-        r#"
-         let asyncRecv = 0;
-         Deno.core.setAsyncHandler(1, (buf) => {
-           assert(buf.byteLength === 1);
-           assert(buf[0] === 43);
-           asyncRecv++;
-         });
-         // Large message that will overflow the shared space.
-         let control = new Uint8Array(100 * 1024 * 1024);
-         let response = Deno.core.dispatch(1, control);
-         // Async messages always have null response.
-         assert(response == null);
-         assert(asyncRecv == 0);
-         "#,
-      ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
-      assert!(match isolate.poll_unpin(cx) {
-        Poll::Ready(Ok(_)) => true,
-        _ => false,
-      });
-      js_check(isolate.execute("check.js", "assert(asyncRecv == 1);"));
-    });
+  fn test_recv_validation() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute(
+      "recv_validation.js",
+      r#"
+        let thrown;
+        try {
+          Deno.core.recv(42);
+        } catch (e) {
+          thrown = e;
+        }
+        if (!(thrown instanceof TypeError)) throw Error("expected TypeError");
+        // The built-in JavaScript has registered its recv function already.
+        thrown = undefined;
+        try {
+          Deno.core.recv(() => {});
+        } catch (e) {
+          thrown = e;
+        }
+        if (thrown !== "Deno.core.recv already called.") throw Error(thrown);
+      "#,
+    ));
+
+    // A realm has its own recv function, even after the main context has
+    // registered one.
+    let realm = isolate.create_realm();
+    js_check(isolate.execute_in_realm(
+      realm,
+      "realm_recv.js",
+      "Deno.core.recv(() => {});",
+    ));
+
+    // A context the isolate doesn't know about has nowhere to deliver
+    // responses to.
+    let v8_isolate = isolate.v8_isolate.as_mut().unwrap();
+    let mut hs = v8::HandleScope::new(v8_isolate);
+    let scope = hs.enter();
+    let context = bindings::initialize_context(scope);
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+    let source = v8::String::new(
+      scope,
+      "try { Deno.core.recv(() => {}); 'ok' } catch (e) { e.message }",
+    )
+    .unwrap();
+    let mut script = v8::Script::compile(scope, context, source, None).unwrap();
+    let result = script.run(scope, context).unwrap();
+    let message = result.to_string(scope).unwrap().to_rust_string_lossy(scope);
+    assert_eq!(
+      message,
+      "Deno.core.recv called from a context that can't receive responses"
+    );
   }
 
   #[test]
-  fn overflow_res_async() {
-    run_in_task(|_cx| {
-      // TODO(ry) This test is quite slow due to memcpy-ing 100MB into JS. We
-      // should optimize this.
-      let (mut isolate, dispatch_count) = setup(Mode::OverflowResAsync);
-      js_check(isolate.execute(
-        "overflow_res_async.js",
-        r#"
-         let asyncRecv = 0;
-         Deno.core.setAsyncHandler(1, (buf) => {
-           assert(buf.byteLength === 100 * 1024 * 1024);
-           assert(buf[0] === 4);
-           asyncRecv++;
-         });
-         // Large message that will overflow the shared space.
-         let control = new Uint8Array([42]);
-         let response = Deno.core.dispatch(1, control);
-         assert(response == null);
-         assert(asyncRecv == 0);
-         "#,
-      ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
-      poll_until_ready(&mut isolate, 3).unwrap();
-      js_check(isolate.execute("check.js", "assert(asyncRecv == 1);"));
-    });
+  fn test_set_recv() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    js_check(isolate.execute(
+      "recv.js",
+      r#"
+        globalThis.received = [];
+        globalThis.recv = (opId, buf) => { received.push([opId, buf[0]]); };
+        globalThis.notAFunction = 1;
+      "#,
+    ));
+    let not_a_function = isolate.get_global("notAFunction").unwrap();
+    assert!(isolate.set_recv(not_a_function).is_err());
+
+    let recv = isolate.get_global("recv").unwrap();
+    js_check(isolate.set_recv(recv));
+    let backing_store =
+      v8::ArrayBuffer::new_backing_store_from_boxed_slice(Box::new([42]))
+        .make_shared();
+    js_check(isolate.respond_zero_copy(7, backing_store));
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+        if (received.length !== 1) throw Error("recv not called");
+        if (received[0][0] !== 7 || received[0][1] !== 42) throw Error("bad");
+      "#,
+    ));
   }
 
   #[test]
-  fn overflow_res_multiple_dispatch_async() {
-    // TODO(ry) This test is quite slow due to memcpy-ing 100MB into JS. We
-    // should optimize this.
-    run_in_task(|_cx| {
-      let (mut isolate, dispatch_count) = setup(Mode::OverflowResAsync);
-      js_check(isolate.execute(
-        "overflow_res_multiple_dispatch_async.js",
-        r#"
-         let asyncRecv = 0;
-         Deno.core.setAsyncHandler(1, (buf) => {
-           assert(buf.byteLength === 100 * 1024 * 1024);
-           assert(buf[0] === 4);
-           asyncRecv++;
-         });
-         // Large message that will overflow the shared space.
-         let control = new Uint8Array([42]);
-         let response = Deno.core.dispatch(1, control);
-         assert(response == null);
-         assert(asyncRecv == 0);
-         // Dispatch another message to verify that pending ops
-         // are done even if shared space overflows
-         Deno.core.dispatch(1, control);
-         "#,
-      ));
-      assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
-      poll_until_ready(&mut isolate, 3).unwrap();
-      js_check(isolate.execute("check.js", "assert(asyncRecv == 2);"));
+  fn test_set_dispatcher() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let dispatch_count = Rc::new(AtomicUsize::new(0));
+    let dispatch_count_ = dispatch_count.clone();
+    let test_id = isolate.register_op("test", |_control, _zero_copy| {
+      Op::Sync(vec![99].into_boxed_slice())
+    });
+    assert_eq!(test_id, 1);
+    isolate.set_dispatcher(move |op_id, control, _zero_copy| {
+      dispatch_count_.fetch_add(1, Ordering::Relaxed);
+      assert_eq!(control, &[42]);
+      let buf = vec![op_id as u8].into_boxed_slice();
+      Op::Sync(buf)
     });
+    js_check(isolate.execute(
+      "set_dispatcher.js",
+      r#"
+        const control = new Uint8Array([42]);
+        const res1 = Deno.core.send(7, control);
+        const res2 = Deno.core.send(8, control);
+        if (res1[0] !== 7 || res2[0] !== 8) {
+          throw Error("bad response");
+        }
+        // Registered ops don't reach the dispatcher.
+        if (Deno.core.send(1, control)[0] !== 99) {
+          throw Error("bad registered op response");
+        }
+        // The op map is still served by the registry.
+        if (typeof Deno.core.ops() !== "object") {
+          throw Error("bad op map");
+        }
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 2);
   }
 
   #[test]
-  fn test_pre_dispatch() {
-    run_in_task(|mut cx| {
-      let (mut isolate, _dispatch_count) = setup(Mode::OverflowResAsync);
-      js_check(isolate.execute(
-        "bad_op_id.js",
-        r#"
-          let thrown;
-          try {
-            Deno.core.dispatch(100, []);
-          } catch (e) {
-            thrown = e;
-          }
-          assert(String(thrown) === "TypeError: Unknown op id: 100");
-         "#,
-      ));
-      if let Poll::Ready(Err(_)) = isolate.poll_unpin(&mut cx) {
-        unreachable!();
-      }
-    });
+  fn source_map_url() {
+    use crate::bindings::source_map_url_from_source;
+    assert_eq!(
+      source_map_url_from_source("a();\n//# sourceMappingURL=a.js.map\n"),
+      Some("a.js.map")
+    );
+    assert_eq!(source_map_url_from_source("a();\n"), None);
+    assert_eq!(source_map_url_from_source("//# sourceMappingURL="), None);
+    assert_eq!(
+      source_map_url_from_source("//# sourceMappingURL=a.js.map\na();"),
+      None
+    );
+    assert_eq!(
+      source_map_url_from_source(
+        "a();\n  //# sourceMappingURL= b.js.map  \n\n"
+      ),
+      Some("b.js.map")
+    );
+
+    // The origin built from the URL must still describe the script itself.
+    let mut isolate = Isolate::new(StartupData::None, false);
+    let err = isolate
+      .execute(
+        "source_map_url.js",
+        "\nthrow new Error('x');\n//# sourceMappingURL=source_map_url.js.map",
+      )
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(
+      js_error.script_resource_name.as_deref(),
+      Some("source_map_url.js")
+    );
+    assert_eq!(js_error.line_number, Some(2));
   }
 
   #[test]
-  fn test_js() {
-    run_in_task(|mut cx| {
-      let (mut isolate, _dispatch_count) = setup(Mode::Async);
-      js_check(
-        isolate.execute(
-          "shared_queue_test.js",
-          include_str!("shared_queue_test.js"),
-        ),
-      );
-      if let Poll::Ready(Err(_)) = isolate.poll_unpin(&mut cx) {
-        unreachable!();
-      }
+  fn zero_copy_buf_clone() {
+    let mut isolate = Isolate::new(StartupData::None, false);
+    isolate.register_op("test", |_control, zero_copy| {
+      let mut buf = zero_copy.unwrap();
+      let buf2 = buf.clone();
+      buf[0] = 42;
+      drop(buf);
+      assert_eq!(buf2[0], 42);
+      assert_eq!(buf2.len(), 3);
+      Op::Sync(Box::new([]))
     });
+    js_check(isolate.execute(
+      "zero_copy_buf_clone.js",
+      r#"
+        const zeroCopy = new Uint8Array([0, 1, 2]);
+        Deno.core.send(1, new Uint8Array([]), zeroCopy);
+        if (zeroCopy[0] !== 42) {
+          throw Error("zero copy buf not shared");
+        }
+        "#,
+    ));
   }
 
   #[test]
-  fn syntax_error() {
-    let mut isolate = Isolate::new(StartupData::None, false);
-    let src = "hocuspocus(";
-    let r = isolate.execute("i.js", src);
-    let e = r.unwrap_err();
-    let js_error = e.downcast::<JSError>().unwrap();
-    assert_eq!(js_error.end_column, Some(11));
+  fn isolate_builder() {
+    let mut isolate = IsolateBuilder::new()
+      .startup_data(StartupData::Script(Script {
+        source: "globalThis.started = true",
+        filename: "startup.js",
+      }))
+      .shared_queue_size(1024)
+      .build();
+    js_check(isolate.execute("check.js", "if (!started) throw Error('x')"));
   }
 
   #[test]
-  fn test_encode_decode() {
-    run_in_task(|mut cx| {
-      let (mut isolate, _dispatch_count) = setup(Mode::Async);
-      js_check(isolate.execute(
-        "encode_decode_test.js",
-        include_str!("encode_decode_test.js"),
-      ));
-      if let Poll::Ready(Err(_)) = isolate.poll_unpin(&mut cx) {
-        unreachable!();
-      }
-    });
+  fn heap_limits() {
+    let heap_limits = HeapLimits {
+      max_old_generation_size: 20 * 1024 * 1024,
+      ..Default::default()
+    };
+    let mut isolate = IsolateBuilder::new().heap_limits(heap_limits).build();
+    let cb_handle = isolate.v8_isolate.as_mut().unwrap().thread_safe_handle();
+
+    let callback_invoke_count = Rc::new(AtomicUsize::default());
+    let inner_invoke_count = Rc::clone(&callback_invoke_count);
+
+    isolate.add_near_heap_limit_callback(
+      move |current_limit, _initial_limit| {
+        inner_invoke_count.fetch_add(1, Ordering::SeqCst);
+        let ok = cb_handle.terminate_execution();
+        assert!(ok);
+        current_limit * 2
+      },
+    );
+    let err = isolate
+      .execute(
+        "script name",
+        r#"let s = ""; while(true) { s += "Hello"; }"#,
+      )
+      .expect_err("script should fail");
+    assert_eq!("Uncaught Error: execution terminated", err.to_string());
+    assert!(callback_invoke_count.load(Ordering::SeqCst) > 0)
   }
 
   #[test]
-  fn will_snapshot() {
-    let snapshot = {
-      let mut isolate = Isolate::new(StartupData::None, true);
-      js_check(isolate.execute("a.js", "a = 1 + 2"));
-      isolate.snapshot()
+  fn terminate_on_heap_limit() {
+    let heap_limits = HeapLimits {
+      max_old_generation_size: 20 * 1024 * 1024,
+      ..Default::default()
     };
-
-    let startup_data = StartupData::OwnedSnapshot(snapshot);
-    let mut isolate2 = Isolate::new(startup_data, false);
-    js_check(isolate2.execute("check.js", "if (a != 3) throw Error('x')"));
+    let mut isolate = IsolateBuilder::new()
+      .heap_limits(heap_limits)
+      .terminate_on_heap_limit(true)
+      .build();
+    let err = isolate
+      .execute(
+        "script name",
+        r#"(() => { let s = ""; while(true) { s += "Hello"; } })()"#,
+      )
+      .expect_err("script should fail");
+    assert!(err.downcast_ref::<HeapLimitExceeded>().is_some());
+
+    // Later terminations aren't blamed on the heap limit.
+    isolate.cancel_terminate_execution();
+    isolate
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .thread_safe_handle()
+      .terminate_execution();
+    let err = isolate.execute("terminated.js", "1").unwrap_err();
+    assert!(err.downcast::<JSError>().unwrap().is_terminated);
   }
 }
 
@@ -1234,3 +4437,43 @@ impl fmt::Debug for ErrWithV8Handle {
     self.err.fmt(f)
   }
 }
+
+/// The error returned by `Isolate::set_shared_queue_size()` while op
+/// responses are waiting in the shared queue.
+#[derive(Debug)]
+pub struct SharedQueueNotEmpty;
+
+impl Error for SharedQueueNotEmpty {}
+
+impl fmt::Display for SharedQueueNotEmpty {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "The shared queue still holds undelivered op responses")
+  }
+}
+
+/// The error returned when a `RealmId` doesn't name a realm of the isolate,
+/// e.g. because it was created before a snapshot.
+#[derive(Debug)]
+pub struct UnknownRealm(pub RealmId);
+
+impl Error for UnknownRealm {}
+
+impl fmt::Display for UnknownRealm {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Unknown realm: {}", self.0)
+  }
+}
+
+/// The error returned once an isolate built with
+/// `IsolateBuilder::terminate_on_heap_limit()` was terminated because its
+/// heap reached the limit.
+#[derive(Debug)]
+pub struct HeapLimitExceeded;
+
+impl Error for HeapLimitExceeded {}
+
+impl fmt::Display for HeapLimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Out of memory: the isolate reached its heap limit")
+  }
+}
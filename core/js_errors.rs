@@ -6,8 +6,10 @@
 //   console.log(err.stack);
 // It would require calling into Rust from Error.prototype.prepareStackTrace.
 
+use crate::bindings::v8_key;
 use crate::ErrBox;
 use rusty_v8 as v8;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::error::Error;
@@ -19,6 +21,11 @@ use std::fmt;
 #[derive(Debug, PartialEq, Clone)]
 pub struct JSError {
   pub message: String,
+  /// The `name`, `message` and `stack` properties of the thrown value, if it
+  /// is a native error (e.g. `throw new TypeError("x")`).
+  pub name: Option<String>,
+  pub exception_message: Option<String>,
+  pub stack: Option<String>,
   pub source_line: Option<String>,
   pub script_resource_name: Option<String>,
   pub line_number: Option<i64>,
@@ -26,6 +33,15 @@ pub struct JSError {
   pub end_column: Option<i64>,   // 0-based
   pub frames: Vec<JSStackFrame>,
   pub formatted_frames: Vec<String>,
+  /// Whether the exception was thrown because execution was terminated, e.g.
+  /// through `v8::IsolateHandle::terminate_execution()`.
+  pub is_terminated: bool,
+  /// The error's `cause`, if any, e.g. for
+  /// `throw new Error("x", { cause: inner })`.
+  pub cause: Option<Box<JSError>>,
+  /// The errors contained in an `AggregateError`, e.g. from a rejected
+  /// `Promise.any()`.
+  pub errors: Vec<JSError>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -46,16 +62,65 @@ pub struct JSStackFrame {
   pub promise_index: Option<i64>,
 }
 
+/// Limits how deep the `cause` chain and `errors` of an error are followed.
+const MAX_CAUSE_DEPTH: usize = 10;
+
 fn get_property<'a>(
   scope: &mut impl v8::ToLocal<'a>,
   context: v8::Local<v8::Context>,
   object: v8::Local<v8::Object>,
-  key: &str,
+  key: &'static str,
 ) -> Option<v8::Local<'a, v8::Value>> {
-  let key = v8::String::new(scope, key).unwrap();
+  let key = v8_key(scope, key);
   object.get(scope, context, key.into())
 }
 
+fn get_string_property<'a>(
+  scope: &mut impl v8::ToLocal<'a>,
+  context: v8::Local<v8::Context>,
+  object: v8::Local<v8::Object>,
+  key: &'static str,
+) -> Option<String> {
+  let value = get_property(scope, context, object, key)?;
+  v8::Local::<v8::String>::try_from(value)
+    .ok()
+    .map(|s| s.to_rust_string_lossy(scope))
+}
+
+fn get_integer_property<'a>(
+  scope: &mut impl v8::ToLocal<'a>,
+  context: v8::Local<v8::Context>,
+  object: v8::Local<v8::Object>,
+  key: &'static str,
+) -> Option<i64> {
+  let value = get_property(scope, context, object, key)?;
+  v8::Local::<v8::Integer>::try_from(value)
+    .ok()
+    .map(|n| n.value())
+}
+
+/// Returns whether `value` is an object that was already visited while
+/// serializing an error.
+fn is_seen(seen: &HashSet<i32>, value: v8::Local<v8::Value>) -> bool {
+  match v8::Local::<v8::Object>::try_from(value) {
+    Ok(object) => seen.contains(&object.get_identity_hash()),
+    Err(_) => false,
+  }
+}
+
+/// Returns false unless the property is the boolean `true`.
+fn get_bool_property<'a>(
+  scope: &mut impl v8::ToLocal<'a>,
+  context: v8::Local<v8::Context>,
+  object: v8::Local<v8::Object>,
+  key: &'static str,
+) -> bool {
+  get_property(scope, context, object, key)
+    .and_then(|v| v8::Local::<v8::Boolean>::try_from(v).ok())
+    .map(|b| b.is_true())
+    .unwrap_or(false)
+}
+
 impl JSError {
   pub(crate) fn create(js_error: Self) -> ErrBox {
     ErrBox::from(js_error)
@@ -64,6 +129,18 @@ impl JSError {
   pub fn from_v8_exception(
     scope: &mut impl v8::InIsolate,
     exception: v8::Local<v8::Value>,
+  ) -> Self {
+    Self::from_v8_exception_inner(scope, exception, &mut HashSet::new(), 0)
+  }
+
+  /// `seen` holds the identity hashes of the errors visited so far, to guard
+  /// against cyclic causes, and `depth` is how many `cause` or `errors` links
+  /// were followed to reach `exception`.
+  fn from_v8_exception_inner(
+    scope: &mut impl v8::InIsolate,
+    exception: v8::Local<v8::Value>,
+    seen: &mut HashSet<i32>,
+    depth: usize,
   ) -> Self {
     // Create a new HandleScope because we're creating a lot of new local
     // handles below.
@@ -73,160 +150,208 @@ impl JSError {
 
     let msg = v8::Exception::create_message(scope, exception);
 
+    let is_native_error = exception.is_native_error();
     let exception: Option<v8::Local<v8::Object>> =
       exception.clone().try_into().ok();
     let _ = exception.map(|e| get_property(scope, context, e, "stack"));
 
-    let maybe_call_sites = exception
-      .and_then(|e| get_property(scope, context, e, "__callSiteEvals"));
-    let maybe_call_sites: Option<v8::Local<v8::Array>> =
-      maybe_call_sites.and_then(|a| a.try_into().ok());
+    let (name, exception_message, stack) = match exception {
+      Some(e) if is_native_error => (
+        get_string_property(scope, context, e, "name"),
+        get_string_property(scope, context, e, "message"),
+        get_string_property(scope, context, e, "stack"),
+      ),
+      _ => (None, None, None),
+    };
+
+    if let Some(e) = exception {
+      seen.insert(e.get_identity_hash());
+    }
+    let follow_nested = is_native_error && depth < MAX_CAUSE_DEPTH;
 
-    let (frames, formatted_frames) = if let Some(call_sites) = maybe_call_sites
+    let cause = match exception {
+      Some(e) if follow_nested => get_property(scope, context, e, "cause")
+        .filter(|cause| !cause.is_undefined())
+        .filter(|cause| !is_seen(seen, *cause))
+        .map(|cause| {
+          Box::new(Self::from_v8_exception_inner(scope, cause, seen, depth + 1))
+        }),
+      _ => None,
+    };
+
+    let mut errors: Vec<JSError> = vec![];
+    let maybe_errors = match exception {
+      Some(e) if follow_nested => get_property(scope, context, e, "errors")
+        .and_then(|a| v8::Local::<v8::Array>::try_from(a).ok()),
+      _ => None,
+    };
+    if let Some(errors_v8) = maybe_errors {
+      // Entries whose getter throws are skipped.
+      let errors_v8: Vec<v8::Local<v8::Value>> = (0..errors_v8.length())
+        .filter_map(|i| errors_v8.get_index(scope, context, i))
+        .collect();
+      for error in errors_v8 {
+        if is_seen(seen, error) {
+          continue;
+        }
+        errors.push(Self::from_v8_exception_inner(
+          scope,
+          error,
+          seen,
+          depth + 1,
+        ));
+      }
+    }
+
+    let maybe_call_sites = exception.and_then(|e| {
+      let call_sites = get_property(scope, context, e, "__callSiteEvals")?;
+      let call_sites: v8::Local<v8::Array> = call_sites.try_into().ok()?;
+      Some((e, call_sites))
+    });
+
+    let (frames, formatted_frames) = if let Some((exception, call_sites)) =
+      maybe_call_sites
     {
       let mut frames: Vec<JSStackFrame> = vec![];
       let mut formatted_frames: Vec<String> = vec![];
 
-      let formatted_frames_v8 =
-        get_property(scope, context, exception.unwrap(), "__formattedFrames");
-      let formatted_frames_v8: v8::Local<v8::Array> = formatted_frames_v8
-        .and_then(|a| a.try_into().ok())
-        .expect("__formattedFrames should be defined if __callSiteEvals is.");
+      // `__formattedFrames` is set together with `__callSiteEvals` by
+      // `prepareStackTrace`, but user code may have tampered with either, so
+      // missing or malformed entries are skipped rather than trusted.
+      let formatted_frames_v8: Option<v8::Local<v8::Array>> =
+        get_property(scope, context, exception, "__formattedFrames")
+          .and_then(|a| a.try_into().ok());
 
       for i in 0..call_sites.length() {
-        let call_site: v8::Local<v8::Object> = call_sites
+        let call_site: v8::Local<v8::Object> = match call_sites
           .get_index(scope, context, i)
-          .unwrap()
-          .try_into()
-          .unwrap();
-        let type_name: Option<v8::Local<v8::String>> =
-          get_property(scope, context, call_site, "typeName")
-            .unwrap()
-            .try_into()
-            .ok();
-        let type_name = type_name.map(|s| s.to_rust_string_lossy(scope));
-        let function_name: Option<v8::Local<v8::String>> =
-          get_property(scope, context, call_site, "functionName")
-            .unwrap()
-            .try_into()
-            .ok();
-        let function_name =
-          function_name.map(|s| s.to_rust_string_lossy(scope));
-        let method_name: Option<v8::Local<v8::String>> =
-          get_property(scope, context, call_site, "methodName")
-            .unwrap()
-            .try_into()
-            .ok();
-        let method_name = method_name.map(|s| s.to_rust_string_lossy(scope));
-        let file_name: Option<v8::Local<v8::String>> =
-          get_property(scope, context, call_site, "fileName")
-            .unwrap()
-            .try_into()
-            .ok();
-        let file_name = file_name.map(|s| s.to_rust_string_lossy(scope));
-        let line_number: Option<v8::Local<v8::Integer>> =
-          get_property(scope, context, call_site, "lineNumber")
-            .unwrap()
-            .try_into()
-            .ok();
-        let line_number = line_number.map(|n| n.value());
-        let column_number: Option<v8::Local<v8::Integer>> =
-          get_property(scope, context, call_site, "columnNumber")
-            .unwrap()
-            .try_into()
-            .ok();
-        let column_number = column_number.map(|n| n.value());
-        let eval_origin: Option<v8::Local<v8::String>> =
-          get_property(scope, context, call_site, "evalOrigin")
-            .unwrap()
-            .try_into()
-            .ok();
-        let eval_origin = eval_origin.map(|s| s.to_rust_string_lossy(scope));
+          .and_then(|v| v.try_into().ok())
+        {
+          Some(call_site) => call_site,
+          None => continue,
+        };
         let is_top_level: Option<v8::Local<v8::Boolean>> =
           get_property(scope, context, call_site, "isTopLevel")
-            .unwrap()
-            .try_into()
-            .ok();
-        let is_top_level = is_top_level.map(|b| b.is_true());
-        let is_eval: v8::Local<v8::Boolean> =
-          get_property(scope, context, call_site, "isEval")
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let is_eval = is_eval.is_true();
-        let is_native: v8::Local<v8::Boolean> =
-          get_property(scope, context, call_site, "isNative")
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let is_native = is_native.is_true();
-        let is_constructor: v8::Local<v8::Boolean> =
-          get_property(scope, context, call_site, "isConstructor")
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let is_constructor = is_constructor.is_true();
-        let is_async: v8::Local<v8::Boolean> =
-          get_property(scope, context, call_site, "isAsync")
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let is_async = is_async.is_true();
-        let is_promise_all: v8::Local<v8::Boolean> =
-          get_property(scope, context, call_site, "isPromiseAll")
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let is_promise_all = is_promise_all.is_true();
-        let promise_index: Option<v8::Local<v8::Integer>> =
-          get_property(scope, context, call_site, "columnNumber")
-            .unwrap()
-            .try_into()
-            .ok();
-        let promise_index = promise_index.map(|n| n.value());
+            .and_then(|v| v.try_into().ok());
         frames.push(JSStackFrame {
-          type_name,
-          function_name,
-          method_name,
-          file_name,
-          line_number,
-          column_number,
-          eval_origin,
-          is_top_level,
-          is_eval,
-          is_native,
-          is_constructor,
-          is_async,
-          is_promise_all,
-          promise_index,
+          type_name: get_string_property(scope, context, call_site, "typeName"),
+          function_name: get_string_property(
+            scope,
+            context,
+            call_site,
+            "functionName",
+          ),
+          method_name: get_string_property(
+            scope,
+            context,
+            call_site,
+            "methodName",
+          ),
+          file_name: get_string_property(scope, context, call_site, "fileName"),
+          line_number: get_integer_property(
+            scope,
+            context,
+            call_site,
+            "lineNumber",
+          ),
+          column_number: get_integer_property(
+            scope,
+            context,
+            call_site,
+            "columnNumber",
+          ),
+          eval_origin: get_string_property(
+            scope,
+            context,
+            call_site,
+            "evalOrigin",
+          ),
+          is_top_level: is_top_level.map(|b| b.is_true()),
+          is_eval: get_bool_property(scope, context, call_site, "isEval"),
+          is_native: get_bool_property(scope, context, call_site, "isNative"),
+          is_constructor: get_bool_property(
+            scope,
+            context,
+            call_site,
+            "isConstructor",
+          ),
+          is_async: get_bool_property(scope, context, call_site, "isAsync"),
+          is_promise_all: get_bool_property(
+            scope,
+            context,
+            call_site,
+            "isPromiseAll",
+          ),
+          promise_index: get_integer_property(
+            scope,
+            context,
+            call_site,
+            "promiseIndex",
+          ),
         });
-        let formatted_frame: v8::Local<v8::String> = formatted_frames_v8
-          .get_index(scope, context, i)
-          .unwrap()
-          .try_into()
-          .unwrap();
-        let formatted_frame = formatted_frame.to_rust_string_lossy(scope);
-        formatted_frames.push(formatted_frame)
+        let formatted_frame: Option<v8::Local<v8::String>> =
+          formatted_frames_v8
+            .and_then(|a| a.get_index(scope, context, i))
+            .and_then(|v| v.try_into().ok());
+        if let Some(formatted_frame) = formatted_frame {
+          formatted_frames.push(formatted_frame.to_rust_string_lossy(scope));
+        }
       }
       (frames, formatted_frames)
     } else {
       (vec![], vec![])
     };
 
+    let script_resource_name = msg
+      .get_script_resource_name(scope)
+      .and_then(|v| v8::Local::<v8::String>::try_from(v).ok())
+      .map(|v| v.to_rust_string_lossy(scope));
+    let line_number: Option<i64> =
+      msg.get_line_number(context).and_then(|v| v.try_into().ok());
+    let start_column: Option<i64> = msg.get_start_column().try_into().ok();
+
+    // Without call sites (e.g. the thrown value is not an Error, or it was
+    // thrown from native code) report a single frame at the location of the
+    // message, so that consumers always get at least one frame.
+    let frames = if frames.is_empty() {
+      vec![JSStackFrame {
+        type_name: None,
+        function_name: Some("<anonymous>".to_string()),
+        method_name: None,
+        file_name: script_resource_name.clone(),
+        line_number,
+        // Call site columns are 1-based, message columns are 0-based.
+        column_number: start_column.map(|c| c + 1),
+        eval_origin: None,
+        is_top_level: None,
+        is_eval: false,
+        is_native: false,
+        is_constructor: false,
+        is_async: false,
+        is_promise_all: false,
+        promise_index: None,
+      }]
+    } else {
+      frames
+    };
+
     Self {
       message: msg.get(scope).to_rust_string_lossy(scope),
-      script_resource_name: msg
-        .get_script_resource_name(scope)
-        .and_then(|v| v8::Local::<v8::String>::try_from(v).ok())
-        .map(|v| v.to_rust_string_lossy(scope)),
+      name,
+      exception_message,
+      stack,
+      script_resource_name,
       source_line: msg
         .get_source_line(scope, context)
         .map(|v| v.to_rust_string_lossy(scope)),
-      line_number: msg.get_line_number(context).and_then(|v| v.try_into().ok()),
-      start_column: msg.get_start_column().try_into().ok(),
+      line_number,
+      start_column,
       end_column: msg.get_end_column().try_into().ok(),
       frames,
       formatted_frames,
+      is_terminated: false,
+      cause,
+      errors,
     }
   }
 }
@@ -260,9 +385,10 @@ impl fmt::Display for JSError {
       }
       if self.source_line.is_some() {
         write!(f, "\n{}\n", self.source_line.as_ref().unwrap())?;
+        let start_column = self.start_column.unwrap_or(0);
         let mut s = String::new();
-        for i in 0..self.end_column.unwrap() {
-          if i >= self.start_column.unwrap() {
+        for i in 0..self.end_column.unwrap_or(start_column) {
+          if i >= start_column {
             s.push('^');
           } else {
             s.push(' ');
@@ -278,6 +404,9 @@ impl fmt::Display for JSError {
       // TODO: Strip ANSI color from formatted_frame.
       write!(f, "\n    at {}", formatted_frame)?;
     }
+    if let Some(cause) = &self.cause {
+      write!(f, "\nCaused by: {}", cause)?;
+    }
     Ok(())
   }
 }
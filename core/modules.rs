@@ -14,6 +14,7 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::future::Future;
+use std::io;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::Context;
@@ -39,6 +40,11 @@ pub struct ModuleSource {
   pub code: String,
   pub module_url_specified: String,
   pub module_url_found: String,
+  /// Where `code` starts in the file the loader read it from, e.g. when it
+  /// wraps the file's contents. Positions in stack traces and errors are
+  /// shifted by these, so that they refer to the original file.
+  pub line_offset: i32,
+  pub column_offset: i32,
 }
 
 pub type ModuleSourceFuture = dyn Future<Output = Result<ModuleSource, ErrBox>>;
@@ -58,6 +64,20 @@ pub trait ModuleLoader {
     is_main: bool,
   ) -> Result<ModuleSpecifier, ErrBox>;
 
+  /// Called for every dynamic import, before it is resolved, with the
+  /// host-defined options of the script or module that contains it, see
+  /// `EsIsolate::set_host_defined_options()`. Returning an error rejects the
+  /// import, e.g. to enforce a per-script policy or integrity metadata. By
+  /// default every dynamic import is allowed.
+  fn check_dynamic_import(
+    &self,
+    _specifier: &str,
+    _referrer: &str,
+    _host_defined_options: &[String],
+  ) -> Result<(), ErrBox> {
+    Ok(())
+  }
+
   /// Given ModuleSpecifier, load its source code.
   ///
   /// `is_dyn_import` can be used to check permissions or deny
@@ -80,6 +100,8 @@ enum Kind {
 pub enum LoadState {
   ResolveMain(String, Option<String>),
   ResolveImport(String, String),
+  /// A dynamic import whose specifier has been resolved already.
+  LoadImport(ModuleSpecifier),
   LoadingRoot,
   LoadingImports,
   Done,
@@ -123,6 +145,18 @@ impl RecursiveModuleLoad {
     Self::new(kind, state, loader, Some(id))
   }
 
+  /// Same as `RecursiveModuleLoad::dynamic_import()`, for a specifier that
+  /// the caller has resolved already.
+  pub fn dynamic_import_resolved(
+    id: DynImportId,
+    module_specifier: ModuleSpecifier,
+    loader: Rc<dyn ModuleLoader>,
+  ) -> Self {
+    let kind = Kind::DynamicImport;
+    let state = LoadState::LoadImport(module_specifier);
+    Self::new(kind, state, loader, Some(id))
+  }
+
   pub fn is_dynamic_import(&self) -> bool {
     self.kind != Kind::Main
   }
@@ -152,6 +186,7 @@ impl RecursiveModuleLoad {
       LoadState::ResolveImport(ref specifier, ref referrer) => {
         self.loader.resolve(specifier, referrer, false)?
       }
+      LoadState::LoadImport(ref module_specifier) => module_specifier.clone(),
 
       _ => unreachable!(),
     };
@@ -162,6 +197,8 @@ impl RecursiveModuleLoad {
           code: code.to_owned(),
           module_url_specified: module_specifier.to_string(),
           module_url_found: module_specifier.to_string(),
+          line_offset: 0,
+          column_offset: 0,
         })
         .boxed()
       }
@@ -202,7 +239,9 @@ impl Stream for RecursiveModuleLoad {
   ) -> Poll<Option<Self::Item>> {
     let inner = self.get_mut();
     match inner.state {
-      LoadState::ResolveMain(..) | LoadState::ResolveImport(..) => {
+      LoadState::ResolveMain(..)
+      | LoadState::ResolveImport(..)
+      | LoadState::LoadImport(..) => {
         if let Err(e) = inner.add_root() {
           return Poll::Ready(Some(Err(e)));
         }
@@ -221,6 +260,10 @@ impl Stream for RecursiveModuleLoad {
 }
 
 pub struct ModuleInfo {
+  pub id: ModuleId,
+  /// The identity hash of the V8 module. Unlike `id` it isn't guaranteed to
+  /// be unique, it's only used to find the module from V8 callbacks.
+  pub identity_hash: i32,
   pub main: bool,
   pub name: String,
   pub handle: v8::Global<v8::Module>,
@@ -281,6 +324,16 @@ impl ModuleNameMap {
     self.inner.insert(name, SymbolicModule::Alias(target));
   }
 
+  /// Remove a name, regardless of whether it is an alias.
+  pub fn remove(&mut self, name: &str) {
+    self.inner.remove(name);
+  }
+
+  /// Remove all names and aliases.
+  pub fn clear(&mut self) {
+    self.inner.clear();
+  }
+
   /// Check if a name is an alias to another module.
   pub fn is_alias(&self, name: &str) -> bool {
     let cond = self.inner.get(name);
@@ -292,10 +345,17 @@ impl ModuleNameMap {
 }
 
 /// A collection of JS modules.
-#[derive(Default)]
 pub struct Modules {
   pub(crate) info: HashMap<ModuleId, ModuleInfo>,
   by_name: ModuleNameMap,
+  by_identity_hash: HashMap<i32, ModuleId>,
+  next_id: ModuleId,
+}
+
+impl Default for Modules {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Modules {
@@ -303,6 +363,8 @@ impl Modules {
     Self {
       info: HashMap::new(),
       by_name: ModuleNameMap::new(),
+      by_identity_hash: HashMap::new(),
+      next_id: 1,
     }
   }
 
@@ -310,10 +372,54 @@ impl Modules {
     self.by_name.get(name)
   }
 
+  /// Returns the id of the module whose V8 identity hash is `identity_hash`.
+  /// V8 callbacks only get a `v8::Module`, this maps it back to its id.
+  pub fn get_id_by_identity_hash(
+    &self,
+    identity_hash: i32,
+  ) -> Option<ModuleId> {
+    self.by_identity_hash.get(&identity_hash).copied()
+  }
+
   pub fn get_children(&self, id: ModuleId) -> Option<&Vec<ModuleSpecifier>> {
     self.info.get(&id).map(|i| &i.import_specifiers)
   }
 
+  /// Returns the names of the modules along a shortest chain of imports from
+  /// module `from` to the module called `to`, both included.
+  pub fn import_path(&self, from: ModuleId, to: &str) -> Option<Vec<String>> {
+    let mut parents: HashMap<ModuleId, ModuleId> = HashMap::new();
+    let mut queue = std::collections::VecDeque::new();
+    let mut seen = HashSet::new();
+    queue.push_back(from);
+    seen.insert(from);
+
+    while let Some(id) = queue.pop_front() {
+      if self.get_name(id).map(String::as_str) == Some(to) {
+        let mut path = vec![id];
+        while let Some(&parent) = parents.get(path.last().unwrap()) {
+          path.push(parent);
+        }
+        return Some(
+          path
+            .iter()
+            .rev()
+            .map(|id| self.get_name(*id).unwrap().clone())
+            .collect(),
+        );
+      }
+      for child in self.get_children(id)? {
+        if let Some(child_id) = self.get_id(child.as_str()) {
+          if seen.insert(child_id) {
+            parents.insert(child_id, id);
+            queue.push_back(child_id);
+          }
+        }
+      }
+    }
+    None
+  }
+
   pub fn get_name(&self, id: ModuleId) -> Option<&String> {
     self.info.get(&id).map(|i| &i.name)
   }
@@ -322,27 +428,36 @@ impl Modules {
     self.by_name.get(&specifier.to_string()).is_some()
   }
 
+  /// Registers a module and returns its id. Ids are assigned in increasing
+  /// order starting at 1, and are never reused, even after the module is
+  /// removed.
   pub fn register(
     &mut self,
-    id: ModuleId,
     name: &str,
     main: bool,
     handle: v8::Global<v8::Module>,
+    identity_hash: i32,
     import_specifiers: Vec<ModuleSpecifier>,
-  ) {
+  ) -> ModuleId {
     let name = String::from(name);
     debug!("register_complete {}", name);
 
+    let id = self.next_id;
+    self.next_id += 1;
     self.by_name.insert(name.clone(), id);
+    self.by_identity_hash.insert(identity_hash, id);
     self.info.insert(
       id,
       ModuleInfo {
+        id,
+        identity_hash,
         main,
         name,
         import_specifiers,
         handle,
       },
     );
+    id
   }
 
   pub fn alias(&mut self, name: &str, target: &str) {
@@ -353,6 +468,25 @@ impl Modules {
     self.by_name.is_alias(name)
   }
 
+  /// Unregisters a module. Aliases pointing at the module's name stop
+  /// resolving, but are kept so they become valid again if a module with the
+  /// same name is registered later.
+  pub fn remove(&mut self, id: ModuleId) -> Option<ModuleInfo> {
+    let info = self.info.remove(&id)?;
+    self.by_name.remove(&info.name);
+    if self.get_id_by_identity_hash(info.identity_hash) == Some(id) {
+      self.by_identity_hash.remove(&info.identity_hash);
+    }
+    Some(info)
+  }
+
+  /// Unregisters all modules and aliases, returning the removed modules.
+  pub fn clear(&mut self) -> Vec<ModuleInfo> {
+    self.by_name.clear();
+    self.by_identity_hash.clear();
+    self.info.drain().map(|(_, info)| info).collect()
+  }
+
   pub fn get_info(&self, id: ModuleId) -> Option<&ModuleInfo> {
     if id == 0 {
       return None;
@@ -360,9 +494,78 @@ impl Modules {
     self.info.get(&id)
   }
 
+  /// Returns the id, name and `main` flag of every registered module, ordered
+  /// by name. Aliases are not included.
+  pub fn list(&self) -> Vec<(ModuleId, &str, bool)> {
+    let mut list: Vec<(ModuleId, &str, bool)> = self
+      .info
+      .iter()
+      .map(|(&id, info)| (id, info.name.as_str(), info.main))
+      .collect();
+    list.sort_by(|a, b| a.1.cmp(b.1));
+    list
+  }
+
   pub fn deps(&self, module_specifier: &ModuleSpecifier) -> Option<Deps> {
     Deps::new(self, module_specifier)
   }
+
+  /// Describes the registered modules, see `ModuleManifest`.
+  pub fn manifest(&self) -> ModuleManifest {
+    let list = self.list();
+    ModuleManifest {
+      main: list
+        .iter()
+        .filter(|(_, _, main)| *main)
+        .map(|(_, name, _)| name.to_string())
+        .collect(),
+      modules: list.iter().map(|(_, name, _)| name.to_string()).collect(),
+    }
+  }
+}
+
+/// The modules that were registered in an isolate when it was snapshotted.
+///
+/// V8 only snapshots the context, not compiled modules, so modules are lost
+/// when an isolate is restarted from a snapshot. A host that snapshots a
+/// fully loaded app stores the manifest alongside the snapshot, e.g. as JSON,
+/// and passes it to `EsIsolate::restore_modules()` to load the modules again.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModuleManifest {
+  /// The main modules, ordered by name. Restoring loads these, which also
+  /// loads their dependencies.
+  pub main: Vec<String>,
+  /// All modules, ordered by name.
+  pub modules: Vec<String>,
+}
+
+impl ModuleManifest {
+  pub fn to_json(&self) -> String {
+    serde_json::json!({
+      "main": self.main,
+      "modules": self.modules,
+    })
+    .to_string()
+  }
+
+  pub fn from_json(json: &str) -> Result<Self, ErrBox> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let names = |key: &str| -> Option<Vec<String>> {
+      value
+        .get(key)?
+        .as_array()?
+        .iter()
+        .map(|name| name.as_str().map(String::from))
+        .collect()
+    };
+    match (names("main"), names("modules")) {
+      (Some(main), Some(modules)) => Ok(Self { main, modules }),
+      _ => Err(ErrBox::from(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Invalid module manifest",
+      ))),
+    }
+  }
 }
 
 /// This is a tree structure representing the dependencies of a given module.
@@ -583,6 +786,8 @@ mod tests {
           code: src.0.to_owned(),
           module_url_specified: inner.url.clone(),
           module_url_found: src.1.to_owned(),
+          line_offset: 0,
+          column_offset: 0,
         })),
         None => Poll::Ready(Err(MockError::LoadErr.into())),
       }
@@ -710,6 +915,55 @@ mod tests {
       Some(&vec![ModuleSpecifier::resolve_url("file:///d.js").unwrap()])
     );
     assert_eq!(modules.get_children(d_id), Some(&vec![]));
+    assert_eq!(
+      modules.list(),
+      vec![
+        (a_id, "file:///a.js", true),
+        (b_id, "file:///b.js", false),
+        (c_id, "file:///c.js", false),
+        (d_id, "file:///d.js", false),
+      ]
+    );
+  }
+
+  #[test]
+  fn snapshot_module_manifest() {
+    let (snapshot, manifest) = {
+      let loader = MockLoader::new();
+      let mut isolate =
+        EsIsolate::new(Rc::new(loader), StartupData::None, true);
+      js_check(isolate.execute("setup.js", "globalThis.setupRan = true"));
+      let spec = ModuleSpecifier::resolve_url("file:///a.js").unwrap();
+      let a_id = futures::executor::block_on(isolate.load_module(&spec, None))
+        .expect("Failed to load");
+      js_check(isolate.mod_evaluate(a_id));
+      isolate.snapshot_with_manifest().unwrap()
+    };
+    assert_eq!(manifest.main, vec!["file:///a.js"]);
+    assert_eq!(
+      manifest.modules,
+      vec![
+        "file:///a.js",
+        "file:///b.js",
+        "file:///c.js",
+        "file:///d.js"
+      ]
+    );
+    assert_eq!(
+      ModuleManifest::from_json(&manifest.to_json()).unwrap(),
+      manifest
+    );
+    assert!(ModuleManifest::from_json(r#"{"main":[1]}"#).is_err());
+
+    let loader = MockLoader::new();
+    let loads = loader.loads.clone();
+    let startup_data = StartupData::OwnedSnapshot(snapshot);
+    let mut isolate = EsIsolate::new(Rc::new(loader), startup_data, false);
+    js_check(isolate.execute("check.js", "if (!setupRan) throw Error()"));
+    let ids = futures::executor::block_on(isolate.restore_modules(&manifest))
+      .expect("Failed to restore");
+    assert_eq!(isolate.modules.get_id("file:///a.js"), Some(ids[0]));
+    assert_eq!(loads.lock().unwrap().len(), 4);
   }
 
   const CIRCULAR1_SRC: &str = r#"
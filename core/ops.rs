@@ -14,6 +14,10 @@ pub type OpAsyncFuture = Pin<Box<dyn Future<Output = Buf>>>;
 
 pub enum Op {
   Sync(Buf),
+  /// A sync response that is a single number, e.g. a resource id or a
+  /// length. `Deno.core.send()` returns it as a JavaScript number rather than
+  /// copying it into a Uint8Array.
+  SyncI32(i32),
   Async(OpAsyncFuture),
   /// AsyncUnref is the variation of Async, which doesn't block the program
   /// exiting.
@@ -65,6 +69,12 @@ impl OpRegistry {
     op_map_json.as_bytes().to_owned().into_boxed_slice()
   }
 
+  /// Returns whether an op with the given id has been registered.
+  pub fn contains(&self, op_id: OpId) -> bool {
+    let lock = self.dispatchers.read().unwrap();
+    (op_id as usize) < lock.len()
+  }
+
   /// This function returns None only if op with given id doesn't exist in registry.
   pub fn call(
     &self,
@@ -121,6 +131,8 @@ fn test_op_registry() {
 
   let res = op_registry.call(100, &[], None);
   assert!(res.is_none());
+  assert!(op_registry.contains(test_id));
+  assert!(!op_registry.contains(100));
 }
 
 #[test]
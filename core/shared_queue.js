@@ -36,10 +36,12 @@ SharedQueue Binary Layout
   // It is possible that the Deno namespace has been deleted.
   // Use the above local Deno and core variable instead.
 
+  let sharedBuffer;
   let sharedBytes;
   let shared32;
 
   let asyncHandlers;
+  let abortHandlers;
 
   let initialized = false;
 
@@ -51,17 +53,22 @@ SharedQueue Binary Layout
   }
 
   function init() {
-    const shared = Deno.core.shared;
-    assert(shared.byteLength > 0);
     assert(sharedBytes == null);
     assert(shared32 == null);
-    sharedBytes = new Uint8Array(shared);
-    shared32 = new Int32Array(shared);
+    initViews(Deno.core.shared);
     asyncHandlers = [];
+    abortHandlers = [];
     // Callers should not call Deno.core.recv, use setAsyncHandler.
     Deno.core.recv(handleAsyncMsgFromRust);
   }
 
+  function initViews(shared) {
+    assert(shared.byteLength > 0);
+    sharedBuffer = shared;
+    sharedBytes = new Uint8Array(shared);
+    shared32 = new Int32Array(shared);
+  }
+
   function ops() {
     // op id 0 is a special value to retrieve the map of registered ops.
     const opsMapBytes = Deno.core.send(0, new Uint8Array([]), null);
@@ -170,7 +177,27 @@ SharedQueue Binary Layout
     asyncHandlers[opId] = cb;
   }
 
-  function handleAsyncMsgFromRust(opId, buf) {
+  function setAbortHandler(opId, cb) {
+    maybeInit();
+    assert(opId != null);
+    abortHandlers[opId] = cb;
+  }
+
+  function handleAsyncMsgFromRust(opId, buf, abortError) {
+    if (abortError) {
+      // This is the deno::Isolate::abort_op() case.
+      const abortHandler = abortHandlers[opId];
+      if (abortHandler == null) {
+        throw abortError;
+      }
+      abortHandler(abortError);
+      return;
+    }
+    // The host may have replaced the shared buffer since the last batch.
+    const shared = Deno.core.shared;
+    if (shared !== sharedBuffer) {
+      initViews(shared);
+    }
     if (buf) {
       // This is the overflow_response case of deno::Isolate::poll().
       asyncHandlers[opId](buf);
@@ -190,9 +217,34 @@ SharedQueue Binary Layout
     return Deno.core.send(opId, control, zeroCopy);
   }
 
+  let fastChannelBuffer;
+  let fastChannelBytes = null;
+
+  // Returns a view over the fast channel, or null if the host hasn't set one
+  // up.
+  function fastChannel() {
+    const buffer = Deno.core.getFastChannel();
+    if (buffer !== fastChannelBuffer) {
+      fastChannelBuffer = buffer;
+      fastChannelBytes = buffer ? new Uint8Array(buffer) : null;
+    }
+    return fastChannelBytes;
+  }
+
+  // The control buffer is the first `controlLength` bytes of fastChannel().
+  // The returned view over a sync response aliases the channel, so it is only
+  // valid until the next call.
+  function dispatchFast(opId, controlLength) {
+    const length = Deno.core.sendFast(opId, controlLength);
+    return length === null ? null : fastChannel().subarray(0, length);
+  }
+
   const denoCore = {
     setAsyncHandler,
+    setAbortHandler,
     dispatch,
+    fastChannel,
+    dispatchFast,
     sharedQueue: {
       MAX_RECORDS,
       head,
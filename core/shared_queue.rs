@@ -14,6 +14,17 @@ SharedQueue Binary Layout
 +---------------------------------------------------------------+
 |                        RECORDS (*MAX_RECORDS)               ...
 +---------------------------------------------------------------+
+
+Memory ordering: the queue is only ever accessed from the thread that runs
+the isolate. Rust pushes records and then calls the `Deno.core.recv()`
+callback, which shifts them off before returning, so the two sides never
+access the queue at the same time and plain loads and stores suffice. Hosts
+that produce responses on other threads must hand them to the isolate thread
+(e.g. by resolving the op future) rather than writing to the queue directly.
+
+The buffer has a fixed size. To grow it, `Isolate::set_shared_queue_size()`
+replaces it with a larger one while the queue is empty; the JS side notices
+the new `Deno.core.shared` before it reads the next batch of records.
  */
 
 use crate::ops::OpId;